@@ -0,0 +1,105 @@
+use crate::tls::ClientIdentity;
+
+pub const ANY: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportAccess {
+    ReadOnly,
+    ReadWrite,
+}
+
+// Grants an identity access to an export. Both may be ANY, an identity matches
+// if it is one of the names of the client certificate.
+#[derive(Debug, Clone)]
+pub struct AccessRule {
+    pub identity: String,
+    pub export: String,
+    pub access: ExportAccess,
+}
+
+impl AccessRule {
+    fn matches(&self, identity: Option<&ClientIdentity>, export: &str) -> bool {
+        let identity_matches = self.identity == ANY
+            || identity.is_some_and(|identity| identity.names.contains(&self.identity));
+        identity_matches && (self.export == ANY || self.export == export)
+    }
+}
+
+// Without any rule every client may access every export.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    rules: Vec<AccessRule>,
+}
+
+impl AccessPolicy {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn grant(&mut self, identity: &str, export: &str, access: ExportAccess) -> &mut Self {
+        self.rules.push(AccessRule {
+            identity: identity.to_string(),
+            export: export.to_string(),
+            access,
+        });
+        self
+    }
+
+    // The most permissive access granted by any matching rule.
+    pub fn access(&self, identity: Option<&ClientIdentity>, export: &str) -> Option<ExportAccess> {
+        if self.rules.is_empty() {
+            return Some(ExportAccess::ReadWrite);
+        }
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(identity, export))
+            .map(|rule| rule.access)
+            .max_by_key(|access| *access == ExportAccess::ReadWrite)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_access_policy() {
+        let tenant = ClientIdentity {
+            names: vec!["tenant-a".to_string(), "a.example.com".to_string()],
+        };
+        let other = ClientIdentity {
+            names: vec!["tenant-b".to_string()],
+        };
+
+        let policy = AccessPolicy::new();
+        assert_eq!(
+            policy.access(None, "fs/a.img"),
+            Some(ExportAccess::ReadWrite)
+        );
+
+        let mut policy = AccessPolicy::new();
+        policy
+            .grant("tenant-a", "fs/a.img", ExportAccess::ReadWrite)
+            .grant("a.example.com", ANY, ExportAccess::ReadOnly)
+            .grant(ANY, "fs/public.img", ExportAccess::ReadOnly);
+
+        assert_eq!(
+            policy.access(Some(&tenant), "fs/a.img"),
+            Some(ExportAccess::ReadWrite)
+        );
+        assert_eq!(
+            policy.access(Some(&tenant), "fs/b.img"),
+            Some(ExportAccess::ReadOnly)
+        );
+        assert_eq!(policy.access(Some(&other), "fs/a.img"), None);
+        assert_eq!(
+            policy.access(Some(&other), "fs/public.img"),
+            Some(ExportAccess::ReadOnly)
+        );
+        assert_eq!(policy.access(None, "fs/a.img"), None);
+        assert_eq!(
+            policy.access(None, "fs/public.img"),
+            Some(ExportAccess::ReadOnly)
+        );
+    }
+}
@@ -1,29 +1,422 @@
-#![allow(unused_variables)]
+use std::{
+    fs::{File, OpenOptions},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, error, info};
 
 use crate::utils::IoResult;
 
-use super::{DriverConstructor, DriverImpl, DriverRegistry, Image, ImageDesc};
+use super::{
+    BlockSize, DriverConfig, DriverConstructor, DriverImpl, DriverRegistry, Extent, ExtentFlags,
+    Image, ImageCaps, ImageDesc, ImageImpl, ImageInfo, WriteFlags,
+};
 
-pub struct FsDriver {}
+const DRIVER_NAME: &str = "fs";
+const CONFIG_ROOT: &str = "root";
+const CONFIG_READONLY: &str = "readonly";
+// "true" or "false", detected from the backing device if not set.
+const CONFIG_ROTATIONAL: &str = "rotational";
+// Minimum and preferred block size of all images, a power of two.
+const CONFIG_BLOCK_SIZE: &str = "block_size";
+const ZERO_CHUNK_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct FsDriver {
+    root: PathBuf,
+    readonly: bool,
+    block_size: BlockSize,
+    rotational: Option<bool>,
+}
+
+impl FsDriver {
+    pub fn new(config: &DriverConfig) -> Self {
+        let mut block_size = BlockSize::default();
+        match config.get(CONFIG_BLOCK_SIZE).map(str::parse::<u32>) {
+            Some(Ok(size)) if size.is_power_of_two() && size <= block_size.maximum => {
+                block_size.minimum = size;
+                block_size.preferred = block_size.preferred.max(size);
+            }
+            Some(_) => error!(config = ?config.get(CONFIG_BLOCK_SIZE), "invalid block size"),
+            None => {}
+        }
+        FsDriver {
+            root: PathBuf::from(config.get(CONFIG_ROOT).unwrap_or(".")),
+            readonly: config.get(CONFIG_READONLY) == Some("true"),
+            block_size,
+            rotational: config.get(CONFIG_ROTATIONAL).map(|value| value == "true"),
+        }
+    }
+
+    // Resolve an image name to a path under root, rejecting anything that could
+    // escape it.
+    fn image_path(&self, name: &str) -> IoResult<PathBuf> {
+        let rel = Path::new(name);
+        if name.is_empty()
+            || !rel
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            debug!(name, "invalid image name");
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+
+        // Symlinks may still point outside of root.
+        let root = self.root.canonicalize()?;
+        let path = root.join(rel).canonicalize()?;
+        if !path.starts_with(&root) {
+            debug!(name, ?path, "image is outside of root");
+            return Err(std::io::ErrorKind::PermissionDenied.into());
+        }
+        Ok(path)
+    }
+}
 
 #[async_trait]
 impl DriverImpl for FsDriver {
     fn name(&self) -> &str {
-        "fs"
+        DRIVER_NAME
     }
 
     fn dup(&self) -> Box<dyn DriverImpl> {
-        Box::new(FsDriver {})
+        Box::new(self.clone())
     }
 
     async fn get_image(&self, name: &str) -> IoResult<ImageDesc> {
-        unimplemented!()
+        let path = self.image_path(name)?;
+        let metadata = tokio::fs::metadata(&path).await?;
+        if !metadata.is_file() {
+            debug!(name, ?path, "image is not a regular file");
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        Ok(ImageDesc {
+            driver_name: DRIVER_NAME.to_string(),
+            name: name.to_string(),
+        })
     }
 
     async fn open(&self, image: &ImageDesc) -> IoResult<Image> {
-        unimplemented!()
+        let path = self.image_path(&image.name)?;
+        let readonly = self.readonly;
+        let block_size = self.block_size;
+        let (file, readonly) = tokio::task::spawn_blocking(move || {
+            if !readonly {
+                match OpenOptions::new().read(true).write(true).open(&path) {
+                    Ok(file) => return Ok((file, false)),
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            std::io::ErrorKind::PermissionDenied
+                                | std::io::ErrorKind::ReadOnlyFilesystem
+                        ) =>
+                    {
+                        info!(?path, "image is not writable, open read-only");
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            OpenOptions::new()
+                .read(true)
+                .open(&path)
+                .map(|file| (file, true))
+        })
+        .await??;
+
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        let rotational = self
+            .rotational
+            .unwrap_or_else(|| is_rotational(metadata.dev()));
+
+        Ok(Image::from_impl(Box::new(FsImage {
+            name: image.name.clone(),
+            file: Arc::new(file),
+            size: Arc::new(AtomicUsize::new(metadata.len() as usize)),
+            readonly,
+            block_size,
+            rotational,
+        })))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FsImage {
+    name: String,
+    file: Arc<File>,
+    // Shared with all clones, so that a resize is seen by every connection.
+    size: Arc<AtomicUsize>,
+    readonly: bool,
+    block_size: BlockSize,
+    rotational: bool,
+}
+
+impl FsImage {
+    async fn blocking<T, F>(&self, f: F) -> IoResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&File) -> IoResult<T> + Send + 'static,
+    {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || f(&file)).await?
+    }
+}
+
+// Ask sysfs about the device holding the file. Partitions have no queue of
+// their own, it is found on the parent disk.
+fn is_rotational(dev: u64) -> bool {
+    let dev = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+    ["queue/rotational", "../queue/rotational"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(Path::new(&dev).join(path)).ok())
+        .is_some_and(|rotational| rotational.trim() == "1")
+}
+
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, length: u64) -> IoResult<()> {
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            length as libc::off_t,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn is_unsupported(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS)
+    )
+}
+
+fn sync_if_fua(file: &File, flags: WriteFlags) -> IoResult<()> {
+    if flags.contains(WriteFlags::FUA) {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+fn lseek(file: &File, offset: u64, whence: libc::c_int) -> IoResult<u64> {
+    let res = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(res as u64)
+}
+
+// Walk the file with SEEK_DATA/SEEK_HOLE. Only the file position is touched,
+// which is fine since all I/O is positional.
+fn file_extents(file: &File, offset: u64, length: u64) -> IoResult<Vec<Extent>> {
+    let end = offset + length;
+    let mut extents = Vec::new();
+    let mut pos = offset;
+    while pos < end {
+        let data = match lseek(file, pos, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // No more data after pos.
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => end,
+            Err(err) => return Err(err),
+        };
+        if data > pos {
+            let hole_end = data.min(end);
+            extents.push(Extent {
+                length: hole_end - pos,
+                flags: ExtentFlags::HOLE | ExtentFlags::ZERO,
+            });
+            pos = hole_end;
+            continue;
+        }
+
+        let hole = lseek(file, pos, libc::SEEK_HOLE)?;
+        let data_end = hole.min(end);
+        extents.push(Extent {
+            length: data_end - pos,
+            flags: ExtentFlags::empty(),
+        });
+        pos = data_end;
+    }
+    Ok(extents)
+}
+
+// Deallocate the range unless asked not to, then try to zero it without
+// writing. Writing zeros is the last resort, which FAST_ZERO does not allow.
+fn zero_range(file: &File, offset: u64, length: u64, flags: WriteFlags) -> IoResult<()> {
+    if !flags.contains(WriteFlags::NO_HOLE) {
+        match fallocate(
+            file,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            length,
+        ) {
+            Err(err) if is_unsupported(&err) => {}
+            res => return res,
+        }
+    }
+    match fallocate(
+        file,
+        libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+        offset,
+        length,
+    ) {
+        Err(err) if is_unsupported(&err) => {
+            if flags.contains(WriteFlags::FAST_ZERO) {
+                return Err(std::io::ErrorKind::Unsupported.into());
+            }
+            write_zeroes_slow(file, offset, length)
+        }
+        res => res,
+    }
+}
+
+fn write_zeroes_slow(file: &File, offset: u64, length: u64) -> IoResult<()> {
+    let zeros = vec![0; ZERO_CHUNK_LEN.min(length as usize)];
+    let mut done = 0;
+    while done < length {
+        let len = (length - done).min(zeros.len() as u64) as usize;
+        file.write_all_at(&zeros[..len], offset + done)?;
+        done += len as u64;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ImageImpl for FsImage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn info(&self) -> ImageInfo {
+        ImageInfo {
+            size: self.size.load(Ordering::Acquire),
+            readonly: self.readonly,
+            description: None,
+            block_size: self.block_size,
+            rotational: self.rotational,
+        }
+    }
+
+    fn dup(&self) -> Box<dyn ImageImpl> {
+        Box::new(self.clone())
+    }
+
+    fn caps(&self) -> ImageCaps {
+        // All clones share the file, and fsync covers every write to it.
+        let caps = ImageCaps::READ | ImageCaps::CACHE | ImageCaps::MULTI_CONN;
+        if self.readonly {
+            caps
+        } else {
+            caps | ImageCaps::WRITE
+                | ImageCaps::FLUSH
+                | ImageCaps::TRIM
+                | ImageCaps::WRITE_ZEROES
+                | ImageCaps::FUA
+                | ImageCaps::FAST_ZERO
+                | ImageCaps::RESIZE
+        }
+    }
+
+    async fn read(&self, offset: u64, length: usize) -> IoResult<Bytes> {
+        self.blocking(move |file| {
+            let mut buf = BytesMut::zeroed(length);
+            file.read_exact_at(&mut buf, offset)?;
+            Ok(buf.freeze())
+        })
+        .await
+    }
+
+    async fn write(&self, offset: u64, data: Bytes, flags: WriteFlags) -> IoResult<()> {
+        self.blocking(move |file| {
+            file.write_all_at(&data, offset)?;
+            sync_if_fua(file, flags)
+        })
+        .await
+    }
+
+    async fn flush(&self) -> IoResult<()> {
+        self.blocking(|file| file.sync_data()).await
+    }
+
+    async fn trim(&self, offset: u64, length: u64, flags: WriteFlags) -> IoResult<()> {
+        self.blocking(move |file| {
+            match fallocate(
+                file,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                length,
+            ) {
+                // Trim is only a hint, it is fine to do nothing.
+                Err(err) if is_unsupported(&err) => {}
+                res => res?,
+            }
+            sync_if_fua(file, flags)
+        })
+        .await
+    }
+
+    async fn write_zeroes(&self, offset: u64, length: u64, flags: WriteFlags) -> IoResult<()> {
+        self.blocking(move |file| {
+            zero_range(file, offset, length, flags)?;
+            sync_if_fua(file, flags)
+        })
+        .await
+    }
+
+    async fn resize(&self, size: u64) -> IoResult<()> {
+        let current = self.size.clone();
+        self.blocking(move |file| {
+            file.set_len(size)?;
+            file.sync_all()?;
+            current.store(size as usize, Ordering::Release);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn cache(&self, offset: u64, length: u64) -> IoResult<()> {
+        self.blocking(move |file| {
+            let res = unsafe {
+                libc::posix_fadvise(
+                    file.as_raw_fd(),
+                    offset as libc::off_t,
+                    length as libc::off_t,
+                    libc::POSIX_FADV_WILLNEED,
+                )
+            };
+            if res != 0 {
+                return Err(std::io::Error::from_raw_os_error(res));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn block_status(&self, offset: u64, length: u64) -> IoResult<Vec<Extent>> {
+        self.blocking(move |file| match file_extents(file, offset, length) {
+            Err(err) if is_unsupported(&err) || err.raw_os_error() == Some(libc::EINVAL) => {
+                Ok(vec![Extent {
+                    length,
+                    flags: ExtentFlags::empty(),
+                }])
+            }
+            res => res,
+        })
+        .await
     }
 }
 
@@ -31,14 +424,110 @@ struct FsDriverConstructor {}
 
 impl DriverConstructor for FsDriverConstructor {
     fn name(&self) -> String {
-        format!("fs")
+        DRIVER_NAME.to_string()
     }
 
-    fn construct(&self, _config: &super::DriverConfig) -> Box<dyn DriverImpl> {
-        Box::new(FsDriver {})
+    fn construct(&self, config: &DriverConfig) -> Box<dyn DriverImpl> {
+        Box::new(FsDriver::new(config))
     }
 }
 
 pub fn init_driver(registry: &mut DriverRegistry) {
     registry.register_driver(FsDriverConstructor {})
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("nbdsrv-fs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn test_driver(root: &Path) -> FsDriver {
+        let mut config = DriverConfig::new();
+        config.insert(CONFIG_ROOT, root.to_str().unwrap());
+        FsDriver::new(&config)
+    }
+
+    #[tokio::test]
+    async fn test_fs_image_io() {
+        let root = test_root("io");
+        std::fs::write(root.join("disk.img"), vec![0xffu8; 8192]).unwrap();
+        let driver = test_driver(&root);
+
+        let desc = driver.get_image("disk.img").await.unwrap();
+        let image = driver.open(&desc).await.unwrap();
+        assert_eq!(image.info().size, 8192);
+        assert!(!image.info().readonly);
+        assert!(image.caps().contains(ImageCaps::WRITE));
+
+        image
+            .write(1024, Bytes::from_static(b"hello nbd"), WriteFlags::FUA)
+            .await
+            .unwrap();
+        let data = image.read(1024, 9).await.unwrap();
+        assert_eq!(&data[..], b"hello nbd");
+
+        image
+            .write_zeroes(0, 2048, WriteFlags::empty())
+            .await
+            .unwrap();
+        image
+            .write_zeroes(2048, 2048, WriteFlags::NO_HOLE)
+            .await
+            .unwrap();
+        let data = image.read(0, 4096).await.unwrap();
+        assert!(data.iter().all(|b| *b == 0));
+        let data = image.read(4096, 4096).await.unwrap();
+        assert!(data.iter().all(|b| *b == 0xff));
+
+        image.resize(16384).await.unwrap();
+        assert_eq!(image.info().size, 16384);
+        let data = image.read(8192, 8192).await.unwrap();
+        assert!(data.iter().all(|b| *b == 0));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fs_block_status() {
+        let root = test_root("block-status");
+        let file = File::create(root.join("sparse.img")).unwrap();
+        file.set_len(1024 * 1024).unwrap();
+        file.write_all_at(&[1u8; 4096], 512 * 1024).unwrap();
+        let driver = test_driver(&root);
+
+        let desc = driver.get_image("sparse.img").await.unwrap();
+        let image = driver.open(&desc).await.unwrap();
+        let extents = image.block_status(0, 1024 * 1024).await.unwrap();
+        assert_eq!(extents.iter().map(|e| e.length).sum::<u64>(), 1024 * 1024);
+        // Whatever the filesystem reports, the written block must be data.
+        let mut pos = 0;
+        for extent in extents {
+            if pos <= 512 * 1024 && 512 * 1024 < pos + extent.length {
+                assert!(extent.flags.is_empty());
+            }
+            pos += extent.length;
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fs_reject_traversal() {
+        let root = test_root("traversal");
+        std::fs::write(root.join("disk.img"), b"").unwrap();
+        let driver = test_driver(&root);
+
+        for name in ["", "../disk.img", "/etc/passwd", "./disk.img", "a/../../b"] {
+            assert!(driver.get_image(name).await.is_err(), "{}", name);
+        }
+        assert!(driver.get_image("disk.img").await.is_ok());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
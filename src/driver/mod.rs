@@ -5,15 +5,32 @@ pub mod fs;
 use std::{collections::HashMap, fmt::Debug, ops::Deref, str::FromStr, sync::OnceLock};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 
 use crate::utils::IoResult;
 
 // Driver registry
 
+#[derive(Debug, Clone, Default)]
 pub struct DriverConfig {
     config: HashMap<String, String>,
 }
 
+impl DriverConfig {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> &mut Self {
+        self.config.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.config.get(key).map(String::as_str)
+    }
+}
+
 pub trait DriverConstructor: Send + Sync + 'static {
     fn name(&self) -> String;
     fn construct(&self, config: &DriverConfig) -> Box<dyn DriverImpl>;
@@ -48,7 +65,7 @@ impl DriverRegistry {
     pub fn get_driver(&self, name: &str, config: &DriverConfig) -> Option<Driver> {
         self.driver_constructors
             .iter()
-            .find(|item| &item.name() == name)
+            .find(|item| item.name() == name)
             .map(|constructor| Driver::from_impl(constructor.construct(config)))
     }
 }
@@ -56,7 +73,7 @@ impl DriverRegistry {
 static DRIVER_REGISTRY: OnceLock<DriverRegistry> = OnceLock::new();
 
 pub fn driver_registry() -> &'static DriverRegistry {
-    DRIVER_REGISTRY.get_or_init(|| DriverRegistry::new())
+    DRIVER_REGISTRY.get_or_init(DriverRegistry::new)
 }
 
 pub struct Driver {
@@ -122,7 +139,7 @@ pub struct ImageDesc {
 
 impl ImageDesc {
     pub fn full_name(&self) -> String {
-        format!("{}/{}", self.name, self.driver_name)
+        format!("{}/{}", self.driver_name, self.name)
     }
 }
 
@@ -156,6 +173,20 @@ impl Clone for Image {
     }
 }
 
+impl Image {
+    pub fn from_impl(blkdev_impl: Box<dyn ImageImpl>) -> Self {
+        Self { blkdev_impl }
+    }
+
+    // Hide every way of modifying the image.
+    pub fn into_readonly(self) -> Self {
+        if self.info().readonly {
+            return self;
+        }
+        Self::from_impl(Box::new(ReadOnlyImage { image: self }))
+    }
+}
+
 impl Deref for Image {
     type Target = dyn ImageImpl;
     fn deref(&self) -> &Self::Target {
@@ -169,10 +200,96 @@ impl Debug for Image {
     }
 }
 
+bitflags::bitflags! {
+    // Operations supported by an image, used by the server to decide what to
+    // advertise and which requests to accept.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ImageCaps: u32 {
+        const READ          = 0x0001;
+        const WRITE         = 0x0002;
+        const FLUSH         = 0x0004;
+        const TRIM          = 0x0008;
+        const WRITE_ZEROES  = 0x0010;
+        const CACHE         = 0x0020;
+        // Images opened once are shared by all connections to an export, and
+        // a flush makes writes from every one of them durable.
+        const MULTI_CONN    = 0x0040;
+        // WriteFlags::FUA is honored by write, trim and write_zeroes.
+        const FUA           = 0x0080;
+        // WriteFlags::FAST_ZERO is honored by write_zeroes.
+        const FAST_ZERO     = 0x0100;
+        const RESIZE        = 0x0200;
+    }
+}
+
+bitflags::bitflags! {
+    // Per request behaviour of operations modifying the image.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WriteFlags: u32 {
+        // The data must be durable when the operation returns.
+        const FUA           = 0x0001;
+        // write_zeroes must leave the range allocated.
+        const NO_HOLE       = 0x0002;
+        // write_zeroes must fail with Unsupported instead of being slower than
+        // writing zeros.
+        const FAST_ZERO     = 0x0004;
+    }
+}
+
+bitflags::bitflags! {
+    // Allocation status of a range, matches the flags of the base:allocation
+    // metadata context.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ExtentFlags: u32 {
+        const HOLE          = 0x0001;
+        const ZERO          = 0x0002;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub length: u64,
+    pub flags: ExtentFlags,
+}
+
+// Request size constraints of an image, in bytes. Requests must be aligned to
+// the minimum, the preferred size avoids read-modify-write cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSize {
+    pub minimum: u32,
+    pub preferred: u32,
+    pub maximum: u32,
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        BlockSize {
+            minimum: 1,
+            preferred: 4096,
+            maximum: 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl BlockSize {
+    // Whether requests need to be aligned at all.
+    pub fn needs_alignment(&self) -> bool {
+        self.minimum > 1
+    }
+
+    pub fn is_aligned(&self, value: u64) -> bool {
+        value.is_multiple_of(self.minimum.max(1) as u64)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ImageInfo {
     pub size: usize,
     pub readonly: bool,
+    pub description: Option<String>,
+    pub block_size: BlockSize,
+    // Seeking is expensive, clients may want to sort requests.
+    pub rotational: bool,
 }
 
 #[async_trait]
@@ -180,4 +297,105 @@ pub trait ImageImpl: Send + Sync {
     fn name(&self) -> &str;
     fn info(&self) -> ImageInfo;
     fn dup(&self) -> Box<dyn ImageImpl>;
+    fn caps(&self) -> ImageCaps;
+
+    async fn read(&self, offset: u64, length: usize) -> IoResult<Bytes>;
+    async fn write(&self, offset: u64, data: Bytes, flags: WriteFlags) -> IoResult<()>;
+
+    async fn flush(&self) -> IoResult<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    async fn trim(&self, _offset: u64, _length: u64, _flags: WriteFlags) -> IoResult<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    async fn write_zeroes(&self, _offset: u64, _length: u64, _flags: WriteFlags) -> IoResult<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    // Grow or shrink the image, info() reports the new size afterwards.
+    async fn resize(&self, _size: u64) -> IoResult<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    // Only a hint to prefetch the range.
+    async fn cache(&self, _offset: u64, _length: u64) -> IoResult<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    // Report the allocation status of a range, starting at offset. The extents
+    // may cover less than length, but never more.
+    async fn block_status(&self, _offset: u64, length: u64) -> IoResult<Vec<Extent>> {
+        Ok(vec![Extent {
+            length,
+            flags: ExtentFlags::empty(),
+        }])
+    }
+}
+
+struct ReadOnlyImage {
+    image: Image,
+}
+
+#[async_trait]
+impl ImageImpl for ReadOnlyImage {
+    fn name(&self) -> &str {
+        self.image.name()
+    }
+
+    fn info(&self) -> ImageInfo {
+        ImageInfo {
+            readonly: true,
+            ..self.image.info()
+        }
+    }
+
+    fn dup(&self) -> Box<dyn ImageImpl> {
+        Box::new(ReadOnlyImage {
+            image: self.image.clone(),
+        })
+    }
+
+    fn caps(&self) -> ImageCaps {
+        self.image.caps()
+            - (ImageCaps::WRITE
+                | ImageCaps::TRIM
+                | ImageCaps::WRITE_ZEROES
+                | ImageCaps::FUA
+                | ImageCaps::FAST_ZERO
+                | ImageCaps::RESIZE)
+    }
+
+    async fn read(&self, offset: u64, length: usize) -> IoResult<Bytes> {
+        self.image.read(offset, length).await
+    }
+
+    async fn write(&self, _offset: u64, _data: Bytes, _flags: WriteFlags) -> IoResult<()> {
+        Err(std::io::ErrorKind::PermissionDenied.into())
+    }
+
+    async fn flush(&self) -> IoResult<()> {
+        self.image.flush().await
+    }
+
+    async fn trim(&self, _offset: u64, _length: u64, _flags: WriteFlags) -> IoResult<()> {
+        Err(std::io::ErrorKind::PermissionDenied.into())
+    }
+
+    async fn write_zeroes(&self, _offset: u64, _length: u64, _flags: WriteFlags) -> IoResult<()> {
+        Err(std::io::ErrorKind::PermissionDenied.into())
+    }
+
+    async fn resize(&self, _size: u64) -> IoResult<()> {
+        Err(std::io::ErrorKind::PermissionDenied.into())
+    }
+
+    async fn cache(&self, offset: u64, length: u64) -> IoResult<()> {
+        self.image.cache(offset, length).await
+    }
+
+    async fn block_status(&self, offset: u64, length: u64) -> IoResult<Vec<Extent>> {
+        self.image.block_status(offset, length).await
+    }
 }
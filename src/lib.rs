@@ -1,4 +1,7 @@
+pub mod access;
 pub mod driver;
+pub mod listener;
 pub mod proto;
 pub mod server;
+pub mod tls;
 pub mod utils;
@@ -0,0 +1,405 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::{FileTypeExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tokio::net::{TcpListener, TcpSocket, UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+use crate::{
+    server::{NegotiationMode, Transport},
+    tls::TlsMode,
+    utils::IoResult,
+};
+
+const LISTEN_BACKLOG: u32 = 1024;
+// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Where a server accepts clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    // The socket file is created with the mode, if given, and removed when the
+    // listener goes away.
+    Unix { path: PathBuf, mode: Option<u32> },
+    // Listening sockets passed by systemd socket activation with the name
+    // (FileDescriptorName=, the socket unit name by default). Several sockets
+    // may share a name.
+    Systemd(String),
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+            ListenAddr::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+            ListenAddr::Systemd(name) => write!(f, "systemd:{}", name),
+        }
+    }
+}
+
+// Per listener settings, unset ones fall back to the server wide ones.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerOptions {
+    pub tls_mode: Option<TlsMode>,
+    pub negotiation: Option<NegotiationMode>,
+    // Whether an IPv6 listener refuses IPv4 clients. Left to the system if
+    // unset, which usually means dual-stack.
+    pub v6_only: Option<bool>,
+}
+
+impl ListenerOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn tls_mode(self, tls_mode: TlsMode) -> Self {
+        Self {
+            tls_mode: Some(tls_mode),
+            ..self
+        }
+    }
+
+    pub fn negotiation(self, negotiation: NegotiationMode) -> Self {
+        Self {
+            negotiation: Some(negotiation),
+            ..self
+        }
+    }
+
+    pub fn v6_only(self, v6_only: bool) -> Self {
+        Self {
+            v6_only: Some(v6_only),
+            ..self
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixSocketListener),
+}
+
+impl Listener {
+    // Sockets passed by systemd are taken over, which may be more than one.
+    pub(crate) async fn bind(addr: &ListenAddr, options: &ListenerOptions) -> IoResult<Vec<Self>> {
+        let listeners = match addr {
+            ListenAddr::Tcp(addr) => vec![Listener::Tcp(bind_tcp(*addr, options.v6_only)?)],
+            ListenAddr::Unix { path, mode } => {
+                vec![Listener::Unix(UnixSocketListener::bind(path, *mode).await?)]
+            }
+            ListenAddr::Systemd(name) => {
+                let fds = take_listen_fds(name);
+                if fds.is_empty() {
+                    warn!(name, "no socket passed by systemd");
+                    return Err(std::io::ErrorKind::NotFound.into());
+                }
+                fds.into_iter()
+                    .map(Listener::from_fd)
+                    .collect::<IoResult<_>>()?
+            }
+        };
+        info!(%addr, count = listeners.len(), "listening");
+        Ok(listeners)
+    }
+
+    fn from_fd(fd: OwnedFd) -> IoResult<Self> {
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockname(
+                fd.as_raw_fd(),
+                &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+            }
+            libc::AF_UNIX => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixSocketListener {
+                    listener: UnixListener::from_std(listener)?,
+                    path: None,
+                }))
+            }
+            family => {
+                warn!(family, "unsupported socket passed by systemd");
+                Err(std::io::ErrorKind::Unsupported.into())
+            }
+        }
+    }
+
+    // The peer address is only known for TCP.
+    pub(crate) async fn accept(&self) -> IoResult<(Box<dyn Transport>, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (sock, addr) = listener.accept().await?;
+                Ok((Box::new(sock), Some(addr)))
+            }
+            Listener::Unix(listener) => {
+                let (sock, _) = listener.listener.accept().await?;
+                Ok((Box::new(sock), None))
+            }
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, v6_only: Option<bool>) -> IoResult<TcpListener> {
+    let sock = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let sock = TcpSocket::new_v6()?;
+            if let Some(v6_only) = v6_only {
+                set_v6_only(&sock, v6_only)?;
+            }
+            sock
+        }
+    };
+    sock.set_reuseaddr(true)?;
+    sock.bind(addr)?;
+    sock.listen(LISTEN_BACKLOG)
+}
+
+fn set_v6_only(sock: &TcpSocket, v6_only: bool) -> IoResult<()> {
+    let value = v6_only as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub(crate) struct UnixSocketListener {
+    listener: UnixListener,
+    // Unset for sockets passed by systemd, which owns the file.
+    path: Option<PathBuf>,
+}
+
+impl UnixSocketListener {
+    async fn bind(path: &Path, mode: Option<u32>) -> IoResult<Self> {
+        remove_stale_socket(path).await?;
+        let listener = match mode {
+            Some(mode) => bind_private(path, mode).await?,
+            None => UnixListener::bind(path)?,
+        };
+        Ok(UnixSocketListener {
+            listener,
+            path: Some(path.to_path_buf()),
+        })
+    }
+}
+
+// Bind in a directory only we can enter and move the socket into place once it
+// has its mode, so nobody can connect while the umask applies.
+async fn bind_private(path: &Path, mode: u32) -> IoResult<UnixListener> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    };
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = parent.join(dir_name);
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .await?;
+
+    let tmp_path = dir.join(name);
+    let res = async {
+        let listener = UnixListener::bind(&tmp_path)?;
+        tokio::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(mode)).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(listener)
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    if let Err(err) = tokio::fs::remove_dir(&dir).await {
+        warn!(?dir, ?err, "failed to remove directory");
+    }
+    res
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(err) = std::fs::remove_file(path) {
+            warn!(?path, ?err, "failed to remove unix socket");
+        }
+    }
+}
+
+// A socket left behind by a server which did not exit cleanly nobody listens
+// on, remove it. Anything else at the path is left alone.
+async fn remove_stale_socket(path: &Path) -> IoResult<()> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        warn!(?path, "not a unix socket");
+        return Err(std::io::ErrorKind::AlreadyExists.into());
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => {
+            warn!(?path, "unix socket is in use");
+            Err(std::io::ErrorKind::AddrInUse.into())
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+            info!(?path, "remove stale unix socket");
+            tokio::fs::remove_file(path).await
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Sockets passed by systemd which have not been taken yet.
+static LISTEN_FDS: Mutex<Option<Vec<(String, OwnedFd)>>> = Mutex::new(None);
+
+// Parse the socket activation protocol: LISTEN_PID must be us, LISTEN_FDS
+// sockets start at fd 3, LISTEN_FDNAMES is a colon separated list of names.
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+) -> Vec<(String, RawFd)> {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let Some(count) = fds.and_then(|fds| fds.parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+    let mut names = names.unwrap_or_default().split(':');
+    (0..count)
+        .map(|i| {
+            let name = names.next().filter(|name| !name.is_empty());
+            (
+                name.unwrap_or("unknown").to_string(),
+                SD_LISTEN_FDS_START + i,
+            )
+        })
+        .collect()
+}
+
+// Take the sockets with the name. The environment is read on the first call
+// and left alone, clearing it is unsound with other threads around. Children
+// ignore it anyway as LISTEN_PID is not theirs, and the sockets are closed on
+// exec.
+fn take_listen_fds(name: &str) -> Vec<OwnedFd> {
+    let mut listen_fds = LISTEN_FDS.lock().unwrap();
+    let listen_fds = listen_fds.get_or_insert_with(|| {
+        let fds = parse_listen_fds(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::env::var("LISTEN_FDNAMES").ok().as_deref(),
+        );
+        debug!(?fds, "sockets passed by systemd");
+        fds.into_iter()
+            .map(|(name, fd)| {
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                (name, unsafe { OwnedFd::from_raw_fd(fd) })
+            })
+            .collect()
+    });
+
+    let mut taken = Vec::new();
+    let mut i = 0;
+    while i < listen_fds.len() {
+        if listen_fds[i].0 == name {
+            taken.push(listen_fds.remove(i).1);
+        } else {
+            i += 1;
+        }
+    }
+    taken
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unix_socket_listener() {
+        let path = std::env::temp_dir().join(format!("nbdsrv-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let addr = ListenAddr::Unix {
+            path: path.clone(),
+            mode: Some(0o660),
+        };
+
+        // A socket nobody listens on is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&addr, &ListenerOptions::new())
+            .await
+            .unwrap()
+            .remove(0);
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        let dir = std::env::temp_dir().join(format!(
+            ".nbdsrv-{}.sock.{}",
+            std::process::id(),
+            std::process::id()
+        ));
+        assert!(!dir.exists());
+
+        // A live one is not.
+        assert_eq!(
+            Listener::bind(&addr, &ListenerOptions::new())
+                .await
+                .err()
+                .unwrap()
+                .kind(),
+            std::io::ErrorKind::AddrInUse
+        );
+
+        let client = UnixStream::connect(&path);
+        let (accepted, client) = tokio::join!(listener.accept(), client);
+        assert!(accepted.unwrap().1.is_none());
+        drop(client);
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_parse_listen_fds() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            parse_listen_fds(Some(&pid), Some("3"), Some("nbd:nbd-unix")),
+            vec![
+                ("nbd".to_string(), 3),
+                ("nbd-unix".to_string(), 4),
+                ("unknown".to_string(), 5)
+            ]
+        );
+        assert!(parse_listen_fds(Some("1"), Some("1"), None).is_empty());
+        assert!(parse_listen_fds(None, Some("1"), None).is_empty());
+    }
+}
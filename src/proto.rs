@@ -7,9 +7,15 @@ pub const CLISERV_MAGIC: u64 = 0x00420281861253;
 pub const IHAVEOPT: u64 = 0x49484156454F5054;
 pub const NBD_REQUEST_MAGIC: u32 = 0x25609513;
 pub const NBD_OPT_REPLY_MAGIC: u64 = 0x3e889045565a9;
+pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+pub const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+pub const NBD_EXTENDED_REQUEST_MAGIC: u32 = 0x21e41c71;
+pub const NBD_EXTENDED_REPLY_MAGIC: u32 = 0x6e8a278c;
 
 pub const NBD_NEWSTYLE_PORT: u16 = 10809;
 
+pub const NBD_META_CONTEXT_BASE_ALLOCATION: &str = "base:allocation";
+
 bitflags::bitflags! {
     // Handshake flags:
     // https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md#handshake-flags
@@ -45,6 +51,25 @@ bitflags::bitflags! {
         const SEND_FAST_ZERO    = 0x0800;
         const BLOCK_STATUS_PAYLOAD  = 0x1000;
     }
+
+    // Command flags:
+    // https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md#command-flags
+    #[derive(Debug, Clone, Copy)]
+    pub struct NbdCmdFlag: u16 {
+        const FUA               = 0x0001;
+        const NO_HOLE           = 0x0002;
+        const DF                = 0x0004;
+        const REQ_ONE           = 0x0008;
+        const FAST_ZERO         = 0x0010;
+        const PAYLOAD_LEN       = 0x0020;
+    }
+
+    // Structured reply flags:
+    // https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md#structured-reply-flags
+    #[derive(Debug, Clone, Copy)]
+    pub struct NbdReplyFlag: u16 {
+        const DONE              = 0x0001;
+    }
 }
 
 // Option types:
@@ -91,6 +116,7 @@ pub enum NbdOptReply {
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq, Eq)]
 pub enum NbdInfo {
+    Export = 0,
     Name = 1,
     Description = 2,
     BlockSize = 3,
@@ -103,7 +129,7 @@ pub enum NbdInfo {
 pub enum NbdCmd {
     Read = 0,
     Write = 1,
-    Disk = 2,
+    Disc = 2,
     Flush = 3,
     Trim = 4,
     Cache = 5,
@@ -111,3 +137,85 @@ pub enum NbdCmd {
     BlockStatus = 7,
     Resize = 8,
 }
+
+// Structured reply types:
+// https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md#structured-reply-types
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq, Eq)]
+pub enum NbdReplyType {
+    None = 0,
+    OffsetData = 1,
+    OffsetHole = 2,
+    BlockStatus = 5,
+    BlockStatusExt = 6,
+    Error = 32769,
+    ErrorOffset = 32770,
+}
+
+// Error values:
+// https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md#error-values
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq, Eq)]
+pub enum NbdError {
+    Perm = 1,
+    Io = 5,
+    NoMem = 12,
+    Inval = 22,
+    NoSpc = 28,
+    Overflow = 75,
+    NotSup = 95,
+    Shutdown = 108,
+}
+
+impl From<&std::io::Error> for NbdError {
+    // Prefer the errno when there is one, it is more precise than the kind.
+    fn from(err: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        if let Some(errno) = err.raw_os_error() {
+            match errno {
+                libc::EPERM | libc::EACCES | libc::EROFS => return NbdError::Perm,
+                libc::EIO => return NbdError::Io,
+                libc::ENOMEM => return NbdError::NoMem,
+                libc::EINVAL => return NbdError::Inval,
+                libc::ENOSPC | libc::EFBIG | libc::EDQUOT => return NbdError::NoSpc,
+                libc::EOVERFLOW => return NbdError::Overflow,
+                libc::EOPNOTSUPP | libc::ENOSYS => return NbdError::NotSup,
+                libc::ESHUTDOWN => return NbdError::Shutdown,
+                _ => {}
+            }
+        }
+        match err.kind() {
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => NbdError::Perm,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => NbdError::Inval,
+            ErrorKind::OutOfMemory => NbdError::NoMem,
+            ErrorKind::StorageFull | ErrorKind::FileTooLarge | ErrorKind::QuotaExceeded => {
+                NbdError::NoSpc
+            }
+            ErrorKind::Unsupported => NbdError::NotSup,
+            _ => NbdError::Io,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_io_error_to_nbd_error() {
+        let errno = |errno| NbdError::from(&std::io::Error::from_raw_os_error(errno));
+        assert_eq!(errno(libc::ENOSPC), NbdError::NoSpc);
+        assert_eq!(errno(libc::EIO), NbdError::Io);
+        assert_eq!(errno(libc::EPERM), NbdError::Perm);
+        assert_eq!(errno(libc::EINVAL), NbdError::Inval);
+        assert_eq!(errno(libc::EOVERFLOW), NbdError::Overflow);
+        assert_eq!(errno(libc::ESHUTDOWN), NbdError::Shutdown);
+        assert_eq!(errno(libc::ENXIO), NbdError::Io);
+
+        let kind = |kind: std::io::ErrorKind| NbdError::from(&std::io::Error::from(kind));
+        assert_eq!(kind(std::io::ErrorKind::PermissionDenied), NbdError::Perm);
+        assert_eq!(kind(std::io::ErrorKind::Unsupported), NbdError::NotSup);
+        assert_eq!(kind(std::io::ErrorKind::UnexpectedEof), NbdError::Io);
+    }
+}
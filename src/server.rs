@@ -2,61 +2,142 @@
 
 use std::{
     collections::HashMap,
-    io::ErrorKind,
+    future::Future,
     mem::MaybeUninit,
-    net::Ipv4Addr,
-    ops::Deref,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    path::PathBuf,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
 };
 
 use async_trait::async_trait;
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use num_traits::FromPrimitive;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf},
+    sync::{mpsc, watch, Semaphore},
+    task::JoinSet,
+    time::Instant,
 };
-use tracing::{debug, error, info};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    driver::{Driver, Image, ImageDesc},
+    access::{AccessPolicy, ExportAccess},
+    driver::{BlockSize, Driver, Image, ImageCaps, ImageDesc, ImageInfo, WriteFlags},
+    listener::{ListenAddr, Listener, ListenerOptions},
     proto::{
-        self, NbdClientFlag, NbdCmd, NbdHandshakeFlag, NbdOpt, NbdOptReply, NbdTxFlag, IHAVEOPT,
-        INIT_PASSWD, NBD_REQUEST_MAGIC,
+        self, NbdClientFlag, NbdCmd, NbdCmdFlag, NbdError, NbdHandshakeFlag, NbdInfo, NbdOpt,
+        NbdOptReply, NbdReplyFlag, NbdReplyType, NbdTxFlag, CLISERV_MAGIC, IHAVEOPT, INIT_PASSWD,
+        NBD_EXTENDED_REPLY_MAGIC, NBD_EXTENDED_REQUEST_MAGIC, NBD_META_CONTEXT_BASE_ALLOCATION,
+        NBD_REQUEST_MAGIC, NBD_SIMPLE_REPLY_MAGIC, NBD_STRUCTURED_REPLY_MAGIC,
     },
+    tls::{ClientIdentity, MaybeTlsStream, TlsConfig, TlsMode},
 };
 
 pub type IoError = std::io::Error;
 pub type IoErrorKind = std::io::ErrorKind;
 pub type IoResult<T> = std::io::Result<T>;
 
-// TODO
-const MAX_OPTION_DATA_LEN: usize = 4096;
-const DEFAULT_TX_FLAGS: NbdTxFlag = NbdTxFlag::HAS_FLAGS
-    .union(NbdTxFlag::SEND_FLUSH)
-    .union(NbdTxFlag::SEND_TRIM)
-    .union(NbdTxFlag::SEND_WRITE_ZEROES);
+// Names are at most 4096 bytes, with room for the lengths and requested
+// information around them.
+const MAX_OPTION_DATA_LEN: usize = 8192;
+const MAX_REQUEST_DATA_LEN: usize = 32 * 1024 * 1024;
+const READ_CHUNK_LEN: usize = 256 * 1024;
+const MAX_META_CONTEXT_QUERIES: usize = 64;
+const MAX_BLOCK_STATUS_EXTENTS: usize = 1024;
+const DEFAULT_MAX_INFLIGHT_REQUESTS: usize = 64;
+// Write payloads a connection may hold at once, at least one full request.
+const MAX_INFLIGHT_DATA_LEN: usize = 2 * MAX_REQUEST_DATA_LEN;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_OPTION_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// Refused clients only have to read why, so they get less time.
+const REFUSED_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// Delay before accepting again after a transient error, doubled each time.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+// Transmission flags advertised for each image capability.
+const CAPS_TX_FLAGS: [(ImageCaps, NbdTxFlag); 8] = [
+    (ImageCaps::FLUSH, NbdTxFlag::SEND_FLUSH),
+    (ImageCaps::FUA, NbdTxFlag::SEND_FUA),
+    (ImageCaps::TRIM, NbdTxFlag::SEND_TRIM),
+    (ImageCaps::WRITE_ZEROES, NbdTxFlag::SEND_WRITE_ZEROES),
+    (ImageCaps::MULTI_CONN, NbdTxFlag::CAN_MULTI_CONN),
+    (ImageCaps::RESIZE, NbdTxFlag::SEND_RESIZE),
+    (ImageCaps::CACHE, NbdTxFlag::SEND_CACHE),
+    (
+        ImageCaps::WRITE_ZEROES.union(ImageCaps::FAST_ZERO),
+        NbdTxFlag::SEND_FAST_ZERO,
+    ),
+];
 const ZEROS: [u8; 128] = unsafe { MaybeUninit::zeroed().assume_init() };
 
 trait NbdWrite {
-    async fn nbd_write(&self, sock: &mut TcpStream) -> IoResult<()>;
+    async fn nbd_write<W: AsyncWrite + Unpin + Send>(&self, sock: &mut W) -> IoResult<()>;
 }
 
 trait NbdRead: Sized {
-    async fn nbd_read(sock: &mut TcpStream) -> IoResult<Self>;
+    async fn nbd_read<R: AsyncRead + Unpin + Send>(sock: &mut R) -> IoResult<Self>;
+}
+
+// Any byte stream clients can connect over: TCP, Unix sockets, vsock or an
+// in-memory pipe.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+// The protocol engine works on a type erased transport, which may be upgraded
+// to TLS during negotiation.
+type Connection = MaybeTlsStream<Box<dyn Transport>>;
+
+// Replies of concurrent requests share the write half, each frame is written
+// while holding the lock.
+type ReplyWriter = tokio::sync::Mutex<WriteHalf<Connection>>;
+
+// Handshake spoken by a listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NegotiationMode {
+    // No options, the default export is sent right away.
+    Oldstyle,
+    // Only NBD_OPT_EXPORT_NAME, anything else closes the connection.
+    Newstyle,
+    // Full option haggling. Clients not setting NBD_FLAG_C_FIXED_NEWSTYLE are
+    // treated as plain newstyle clients.
+    #[default]
+    FixedNewstyle,
 }
 
 pub struct ServerBuilder {
     port: u16,
-    handshake_flags: u16,
+    listen: Vec<(ListenAddr, ListenerOptions)>,
+    negotiation: NegotiationMode,
+    default_export: Option<String>,
+    tls: Option<TlsConfig>,
+    tls_mode: TlsMode,
+    access: AccessPolicy,
+    max_inflight_requests: usize,
+    shutdown_timeout: Duration,
+    timeouts: Timeouts,
+    limits: ConnectionLimits,
 }
 
 impl Default for ServerBuilder {
     fn default() -> Self {
         Self {
             port: crate::proto::NBD_NEWSTYLE_PORT,
-            handshake_flags: NbdHandshakeFlag::FIXED_NEWSTYLE.bits(),
+            listen: Vec::new(),
+            negotiation: NegotiationMode::FixedNewstyle,
+            default_export: None,
+            tls: None,
+            tls_mode: TlsMode::Off,
+            access: AccessPolicy::default(),
+            max_inflight_requests: DEFAULT_MAX_INFLIGHT_REQUESTS,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            timeouts: Timeouts::default(),
+            limits: ConnectionLimits::default(),
         }
     }
 }
@@ -66,42 +147,286 @@ impl ServerBuilder {
         Default::default()
     }
 
+    // Port of the TCP listener used when no other listener is configured.
     pub fn port(self, port: u16) -> Self {
         Self { port, ..self }
     }
 
-    pub fn build(self) -> Server {
-        Server {
-            config: Arc::new(ServerConfig::new(self.port, self.handshake_flags)),
-            state: Arc::new(Mutex::new(ServerState::default())),
+    // Add a listener using the server wide settings. Any number of listeners
+    // can be added, the server accepts on all of them.
+    pub fn listen(self, addr: ListenAddr) -> Self {
+        self.listen_with(addr, ListenerOptions::new())
+    }
+
+    pub fn listen_with(mut self, addr: ListenAddr, options: ListenerOptions) -> Self {
+        self.listen.push((addr, options));
+        self
+    }
+
+    // Listen on a Unix socket, optionally restricting it to the file mode.
+    pub fn unix_socket(self, path: impl Into<PathBuf>, mode: Option<u32>) -> Self {
+        self.listen(ListenAddr::Unix {
+            path: path.into(),
+            mode,
+        })
+    }
+
+    // Take over the sockets passed by systemd socket activation with the name.
+    pub fn systemd_socket(self, name: &str, options: ListenerOptions) -> Self {
+        self.listen_with(ListenAddr::Systemd(name.to_string()), options)
+    }
+
+    // Negotiation of listeners not setting their own.
+    pub fn negotiation(self, negotiation: NegotiationMode) -> Self {
+        Self {
+            negotiation,
+            ..self
+        }
+    }
+
+    // Export ("driver/image") served to oldstyle clients and to clients asking
+    // for the empty export name.
+    pub fn default_export(self, export: &str) -> Self {
+        Self {
+            default_export: Some(export.to_string()),
+            ..self
+        }
+    }
+
+    // Load the PEM certificate chain and private key used for NBD_OPT_STARTTLS.
+    pub fn tls_cert(self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> IoResult<Self> {
+        let tls = TlsConfig::load(cert, key)?;
+        Ok(Self {
+            tls: Some(tls),
+            ..self
+        })
+    }
+
+    // Verify client certificates against the PEM CA certificates, the subject
+    // and alternative names become the identity used by access rules.
+    pub fn tls_client_ca(mut self, ca: impl AsRef<Path>) -> IoResult<Self> {
+        let Some(tls) = self.tls.as_mut() else {
+            error!("client ca configured without certificate");
+            return Err(IoErrorKind::InvalidInput.into());
+        };
+        tls.load_client_ca(ca)?;
+        Ok(self)
+    }
+
+    // TLS mode of listeners not setting their own.
+    pub fn tls_mode(self, tls_mode: TlsMode) -> Self {
+        Self { tls_mode, ..self }
+    }
+
+    // Allow clients with the identity to access the export ("driver/image").
+    // Once any rule is added, exports are only visible to granted clients.
+    pub fn grant(mut self, identity: &str, export: &str, access: ExportAccess) -> Self {
+        self.access.grant(identity, export, access);
+        self
+    }
+
+    // Requests handled concurrently on one connection, reading stops when the
+    // limit is reached.
+    pub fn max_inflight_requests(self, max_inflight_requests: usize) -> Self {
+        Self {
+            max_inflight_requests: max_inflight_requests.max(1),
+            ..self
+        }
+    }
+
+    // How long a shutdown waits for connections to finish before cutting
+    // them off.
+    pub fn shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        Self {
+            shutdown_timeout,
+            ..self
+        }
+    }
+
+    // Time from accepting a client until it is through negotiation, None for
+    // no limit.
+    pub fn handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+
+    // Time a negotiating client may take to send the next option.
+    pub fn option_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.option_idle = timeout;
+        self
+    }
+
+    // Time a client in transmission may go without requests while none is in
+    // flight. Unlimited by default, clients like the kernel keep idle
+    // connections for as long as the device exists.
+    pub fn transmission_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.transmission_idle = timeout;
+        self
+    }
+
+    // Time the image may take for a request before it fails with NBD_EIO.
+    // The image is not interrupted, so a write may still land after the
+    // client was told it failed. Calls still running count against the
+    // requests in flight of the connection until they return.
+    pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.request = timeout;
+        self
+    }
+
+    // Clients beyond the limit are told the server is shutting down in reply
+    // to their first option and closed, no limit by default.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.total = Some(max);
+        self
+    }
+
+    // Connections from one client address, clients beyond the limit are
+    // refused by policy and closed. Unix socket clients have no address.
+    pub fn max_connections_per_client(mut self, max: usize) -> Self {
+        self.limits.per_client = Some(max);
+        self
+    }
+
+    // Connections in transmission with one export, clients beyond the limit
+    // are refused by policy when selecting it.
+    pub fn max_connections_per_export(mut self, max: usize) -> Self {
+        self.limits.per_export = Some(max);
+        self
+    }
+
+    // Fails if a listener could never complete a TLS upgrade.
+    pub fn build(mut self) -> IoResult<Server> {
+        let tls_acceptor = self.tls.take().map(|tls| tls.acceptor()).transpose()?;
+        if self.listen.is_empty() {
+            let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.port));
+            self.listen
+                .push((ListenAddr::Tcp(addr), ListenerOptions::new()));
+        }
+        for (addr, options) in &self.listen {
+            let tls_mode = options.tls_mode.unwrap_or(self.tls_mode);
+            let negotiation = options.negotiation.unwrap_or(self.negotiation);
+            if tls_acceptor.is_none() && tls_mode != TlsMode::Off {
+                error!(%addr, ?tls_mode, "tls enabled without certificate");
+                return Err(IoErrorKind::InvalidInput.into());
+            }
+            if negotiation != NegotiationMode::FixedNewstyle && tls_mode != TlsMode::Off {
+                error!(%addr, ?negotiation, "tls needs fixed newstyle negotiation");
+                return Err(IoErrorKind::InvalidInput.into());
+            }
+            if negotiation == NegotiationMode::Oldstyle && self.default_export.is_none() {
+                warn!(%addr, "oldstyle negotiation without default export");
+            }
         }
+        Ok(Server {
+            config: Arc::new(ServerConfig::new(self, tls_acceptor)),
+            state: Arc::new(Mutex::new(ServerState::default())),
+            shutdown: Arc::new(watch::Sender::new(false)),
+        })
     }
 }
 
 pub struct ServerConfig {
-    port: u16,
-    handshake_flags: u16,
+    listen: Vec<(ListenAddr, ListenerOptions)>,
+    negotiation: NegotiationMode,
+    default_export: Option<String>,
+    tls_acceptor: Option<TlsAcceptor>,
+    tls_mode: TlsMode,
+    access: AccessPolicy,
+    max_inflight_requests: usize,
+    shutdown_timeout: Duration,
+    timeouts: Timeouts,
+    limits: ConnectionLimits,
     option_handlers: HashMap<NbdOpt, Box<dyn OptionHandler>>,
+    meta_contexts: Vec<Box<dyn MetaContext>>,
 }
 
 impl ServerConfig {
-    fn new(port: u16, handshake_flags: u16) -> Self {
+    fn new(builder: ServerBuilder, tls_acceptor: Option<TlsAcceptor>) -> Self {
         let mut config = ServerConfig {
-            port,
-            handshake_flags,
+            listen: builder.listen,
+            negotiation: builder.negotiation,
+            default_export: builder.default_export,
+            tls_acceptor,
+            tls_mode: builder.tls_mode,
+            access: builder.access,
+            max_inflight_requests: builder.max_inflight_requests,
+            shutdown_timeout: builder.shutdown_timeout,
+            timeouts: builder.timeouts,
+            limits: builder.limits,
             option_handlers: HashMap::new(),
+            meta_contexts: Vec::new(),
         };
         config.setup_option_handlers();
+        config.setup_meta_contexts();
         config
     }
 
+    fn listener_config(&self, options: &ListenerOptions) -> ListenerConfig {
+        ListenerConfig {
+            tls_mode: options.tls_mode.unwrap_or(self.tls_mode),
+            negotiation: options.negotiation.unwrap_or(self.negotiation),
+        }
+    }
+
+    fn setup_meta_contexts(&mut self) {
+        self.register_meta_context(Box::new(BaseAllocationMetaContext::default()));
+    }
+
+    // Context ids are indexes into the registry.
+    fn register_meta_context(&mut self, context: Box<dyn MetaContext>) -> &mut Self {
+        self.meta_contexts.push(context);
+        self
+    }
+
+    fn meta_context(&self, id: u32) -> Option<&dyn MetaContext> {
+        self.meta_contexts
+            .get(id as usize)
+            .map(|context| context.as_ref())
+    }
+
+    // Find contexts matching a query. An empty query or a namespace query
+    // ("base:") only matches when listing.
+    fn query_meta_contexts(&self, query: &str, list: bool) -> Vec<u32> {
+        self.meta_contexts
+            .iter()
+            .enumerate()
+            .filter(|(_, context)| {
+                let name = context.name();
+                name == query
+                    || (list
+                        && (query.is_empty() || query.ends_with(':'))
+                        && name.starts_with(query))
+            })
+            .map(|(id, _)| id as u32)
+            .collect()
+    }
+
     fn setup_option_handlers(&mut self) {
         self.insert_handler(
             NbdOpt::ExportName,
             Box::new(ExportNameOptionHandler::default()),
         )
         .insert_handler(NbdOpt::Abort, Box::new(AbortOptionHandler::default()))
-        .insert_handler(NbdOpt::List, Box::new(ListOptionHandler::default()));
+        .insert_handler(NbdOpt::List, Box::new(ListOptionHandler::default()))
+        .insert_handler(NbdOpt::Starttls, Box::new(StartTlsOptionHandler::default()))
+        .insert_handler(NbdOpt::Info, Box::new(InfoOptionHandler::default()))
+        .insert_handler(NbdOpt::Go, Box::new(InfoOptionHandler::default()))
+        .insert_handler(
+            NbdOpt::StructuredReply,
+            Box::new(StructuredReplyOptionHandler::default()),
+        )
+        .insert_handler(
+            NbdOpt::ExtendedHeaders,
+            Box::new(ExtendedHeadersOptionHandler::default()),
+        )
+        .insert_handler(
+            NbdOpt::ListMetaContext,
+            Box::new(MetaContextOptionHandler::default()),
+        )
+        .insert_handler(
+            NbdOpt::SetMetaContext,
+            Box::new(MetaContextOptionHandler::default()),
+        );
     }
 
     fn insert_handler(&mut self, opt: NbdOpt, handler: Box<dyn OptionHandler>) -> &mut Self {
@@ -114,8 +439,16 @@ impl ServerConfig {
         server_shard: &mut ServerShard,
         opt: NbdOpt,
         data: Vec<u8>,
-        sock: &mut TcpStream,
+        sock: &mut Connection,
     ) -> IoResult<OptionHandleState> {
+        if server_shard.listener.tls_mode == TlsMode::Required
+            && !sock.is_tls()
+            && !matches!(opt, NbdOpt::Starttls | NbdOpt::Abort)
+        {
+            info!(?opt, "option refused before tls");
+            return refuse_option(opt as u32, NbdOptReply::ErrTlsReqd, "tls required", sock).await;
+        }
+
         if let Some(handler) = self.option_handlers.get(&opt) {
             handler.handle_option(server_shard, opt, data, sock).await
         } else {
@@ -130,6 +463,13 @@ impl ServerConfig {
 struct ServerState {
     default_driver: Option<Driver>,
     images: HashMap<Driver, Vec<ImageDesc>>,
+    // Images currently opened by some connection, by full name. The handle is
+    // shared until the last connection using it goes away.
+    opened: HashMap<String, Weak<Image>>,
+    // Admitted connections, in total, by client address and by export.
+    connections: usize,
+    client_connections: HashMap<IpAddr, usize>,
+    export_connections: HashMap<String, usize>,
 }
 
 impl ServerState {
@@ -140,144 +480,1342 @@ impl ServerState {
             .collect()
     }
 
-    fn list_images_full_name(&self) -> Vec<String> {
-        self.list_images()
-            .into_iter()
-            .map(|(_, image)| image.full_name())
-            .collect()
-    }
-
     fn find_image(&self, name: &str) -> Option<(Driver, ImageDesc)> {
         let desc = ImageDesc::from_str(name).ok()?;
         self.list_images()
             .into_iter()
             .find(|(_drv, _desc)| &desc == _desc)
     }
+
+    fn opened_image(&mut self, desc: &ImageDesc) -> Option<Arc<Image>> {
+        let name = desc.full_name();
+        let image = self.opened.get(&name)?.upgrade();
+        if image.is_none() {
+            self.opened.remove(&name);
+        }
+        image
+    }
+
+    fn admit_connection(
+        &mut self,
+        limits: &ConnectionLimits,
+        client: Option<IpAddr>,
+    ) -> Result<(), Refusal> {
+        if limits.total.is_some_and(|max| self.connections >= max) {
+            return Err(Refusal::Overloaded);
+        }
+        if let Some(client) = client {
+            let count = self.client_connections.get(&client).copied().unwrap_or(0);
+            if limits.per_client.is_some_and(|max| count >= max) {
+                return Err(Refusal::ClientLimit);
+            }
+            self.client_connections.insert(client, count + 1);
+        }
+        self.connections += 1;
+        Ok(())
+    }
+
+    fn release_connection(&mut self, client: Option<IpAddr>) {
+        self.connections -= 1;
+        if let Some(client) = client {
+            release(&mut self.client_connections, &client);
+        }
+    }
+
+    fn admit_export(&mut self, limits: &ConnectionLimits, export: &str) -> Result<(), Refusal> {
+        let count = self.export_connections.get(export).copied().unwrap_or(0);
+        if limits.per_export.is_some_and(|max| count >= max) {
+            return Err(Refusal::ExportLimit);
+        }
+        self.export_connections
+            .insert(export.to_string(), count + 1);
+        Ok(())
+    }
+
+    fn release_export(&mut self, export: &str) {
+        release(&mut self.export_connections, export);
+    }
+
+    // Keep an existing handle if another connection opened the image meanwhile.
+    fn insert_opened_image(&mut self, desc: &ImageDesc, image: Arc<Image>) -> Arc<Image> {
+        if let Some(opened) = self.opened_image(desc) {
+            return opened;
+        }
+        self.opened.retain(|_, image| image.strong_count() > 0);
+        self.opened.insert(desc.full_name(), Arc::downgrade(&image));
+        image
+    }
+}
+
+// Drop a connection from a count, forgetting keys without any.
+fn release<K, Q>(counts: &mut HashMap<K, usize>, key: &Q)
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: std::hash::Hash + Eq + ?Sized,
+{
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+// A connection counted against the connection limits while it lives.
+struct ConnectionSlot {
+    state: Arc<Mutex<ServerState>>,
+    client: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release_connection(self.client);
+    }
 }
 
+// A connection counted against the limit of the export it uses.
+struct ExportSlot {
+    state: Arc<Mutex<ServerState>>,
+    export: String,
+}
+
+impl Drop for ExportSlot {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release_export(&self.export);
+    }
+}
+
+#[derive(Clone)]
 pub struct Server {
     config: Arc<ServerConfig>,
     state: Arc<Mutex<ServerState>>,
+    // Set once a shutdown has been requested.
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+// Stops a server from another task.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    // Stop accepting clients and let connections wind down, see Server::run.
+    pub fn shutdown(&self) {
+        if !self.shutdown.send_replace(true) {
+            info!("shutdown requested");
+        }
+    }
 }
 
 impl Server {
+    pub async fn add_image(&self, drv: &Driver, name: &str) -> IoResult<()> {
+        let desc = drv.get_image(name).await?;
+        info!(?desc, "add image");
+        let mut state = self.state.lock().unwrap();
+        let images = state.images.entry(drv.clone()).or_default();
+        if !images.contains(&desc) {
+            images.push(desc);
+        }
+        Ok(())
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    // Accept clients on every listener, until one of them fails for good or a
+    // shutdown is requested. Either way the listeners are closed first, then
+    // connections get the shutdown timeout to finish what they are doing
+    // before they are cut off. The listener error is returned last.
     pub async fn run(&mut self) -> IoResult<()> {
-        // Listen for client connection.
-        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.config.port)).await?;
-        loop {
-            let (sock, addr) = listener.accept().await?;
-            info!(?addr, "accept new connection");
-            let shard = ServerShard {
-                config: self.config.clone(),
+        let mut listeners = Vec::with_capacity(self.config.listen.len());
+        for (addr, options) in &self.config.listen {
+            let config = self.config.listener_config(options);
+            for listener in Listener::bind(addr, options).await? {
+                listeners.push((addr.clone(), listener, config));
+            }
+        }
+
+        let (accepted_tx, mut accepted) = mpsc::channel(listeners.len());
+        let mut accept_tasks = JoinSet::new();
+        for (addr, listener, config) in listeners {
+            accept_tasks.spawn(accept_loop(addr, listener, config, accepted_tx.clone()));
+        }
+        let mut connections = JoinSet::new();
+        let mut shutdown = self.shutdown.subscribe();
+
+        // A listener failing for good stops the server as a shutdown would.
+        let result = loop {
+            tokio::select! {
+                Some((sock, peer, config)) = accepted.recv() => {
+                    let client = peer.map(|peer| peer.ip());
+                    connections.spawn(self.shard(config, client).handle_connection(sock));
+                }
+                Some(res) = accept_tasks.join_next() => {
+                    if let Err(err) = res.map_err(IoError::from).and_then(|res| res) {
+                        self.shutdown.send_replace(true);
+                        break Err(err);
+                    }
+                }
+                Some(res) = connections.join_next() => log_connection_result(res),
+                _ = shutdown_requested(&mut shutdown) => break Ok(()),
+            }
+        };
+
+        // Dropping the listeners removes their unix sockets.
+        accept_tasks.shutdown().await;
+        info!(
+            connections = connections.len(),
+            timeout = ?self.config.shutdown_timeout,
+            "waiting for connections to finish"
+        );
+        let drain = async {
+            while let Some(res) = connections.join_next().await {
+                log_connection_result(res);
+            }
+        };
+        if tokio::time::timeout(self.config.shutdown_timeout, drain)
+            .await
+            .is_err()
+        {
+            warn!(
+                connections = connections.len(),
+                "shutdown timeout, closing connections"
+            );
+            connections.shutdown().await;
+        }
+        info!("server stopped");
+        result
+    }
+
+    // Speak NBD over an already established connection, until the client goes
+    // away. The server wide listener settings apply, and the connection counts
+    // against the limits but not those of any client. A shutdown is passed on
+    // to the connection, but not waited for.
+    pub fn serve_connection<S: Transport>(
+        &self,
+        sock: S,
+    ) -> impl Future<Output = IoResult<()>> + Send + 'static {
+        let listener = self.config.listener_config(&ListenerOptions::new());
+        self.shard(listener, None).handle_connection(Box::new(sock))
+    }
+
+    fn shard(&self, listener: ListenerConfig, client: Option<IpAddr>) -> ServerShard {
+        let admitted = self
+            .state
+            .lock()
+            .unwrap()
+            .admit_connection(&self.config.limits, client);
+        let connection = admitted
+            .map(|()| ConnectionSlot {
                 state: self.state.clone(),
-                image: None,
-                tx_flags: DEFAULT_TX_FLAGS,
-                client_flags: NbdClientFlag::empty(),
-            };
-            tokio::spawn(shard.handle_connection(sock));
+                client,
+            })
+            .inspect_err(|refusal| info!(?client, ?refusal, "connection over limit"));
+        ServerShard {
+            config: self.config.clone(),
+            listener,
+            state: self.state.clone(),
+            image: None,
+            client_flags: NbdClientFlag::empty(),
+            structured_reply: false,
+            extended_headers: false,
+            meta_export: None,
+            meta_contexts: Vec::new(),
+            identity: None,
+            shutdown: self.shutdown.subscribe(),
+            connection,
+            export: None,
+            backend_slots: Arc::new(Semaphore::new(self.config.max_inflight_requests)),
+        }
+    }
+}
+
+async fn accept_loop(
+    addr: ListenAddr,
+    listener: Listener,
+    config: ListenerConfig,
+    accepted: mpsc::Sender<(Box<dyn Transport>, Option<SocketAddr>, ListenerConfig)>,
+) -> IoResult<()> {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let (sock, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) if is_transient_accept_error(&err) => {
+                warn!(%addr, ?err, ?backoff, "accept failed, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+            Err(err) => {
+                error!(%addr, ?err, "accept failed");
+                return Err(err);
+            }
+        };
+        backoff = ACCEPT_BACKOFF_MIN;
+        info!(%addr, ?peer, "accept new connection");
+        if accepted.send((sock, peer, config)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Errors which go away on their own: running out of file descriptors or
+// memory, or a client gone before it was accepted.
+fn is_transient_accept_error(err: &IoError) -> bool {
+    if let Some(errno) = err.raw_os_error() {
+        if matches!(
+            errno,
+            libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EPROTO | libc::EPERM
+        ) {
+            return true;
+        }
+    }
+    matches!(
+        err.kind(),
+        IoErrorKind::ConnectionAborted | IoErrorKind::ConnectionReset | IoErrorKind::Interrupted
+    )
+}
+
+fn log_connection_result(res: Result<IoResult<()>, tokio::task::JoinError>) {
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => debug!(?err, "connection failed"),
+        Err(err) => error!(?err, "connection task failed"),
+    }
+}
+
+// Resolves once a shutdown has been requested. Never resolves if the server
+// is gone without one.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|shutdown| *shutdown).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+// Limits on the number of connections, None for no limit.
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectionLimits {
+    total: Option<usize>,
+    per_client: Option<usize>,
+    per_export: Option<usize>,
+}
+
+// Why a client is refused service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refusal {
+    Overloaded,
+    ClientLimit,
+    ExportLimit,
+    ShuttingDown,
+}
+
+impl Refusal {
+    fn reply(self) -> NbdOptReply {
+        match self {
+            Refusal::Overloaded | Refusal::ShuttingDown => NbdOptReply::ErrShutdown,
+            Refusal::ClientLimit | Refusal::ExportLimit => NbdOptReply::ErrPolicy,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Refusal::Overloaded => "server overloaded",
+            Refusal::ClientLimit => "too many connections from client",
+            Refusal::ExportLimit => "too many connections to export",
+            Refusal::ShuttingDown => "server shutting down",
+        }
+    }
+}
+
+// Limits on how long a client may take, None for no limit.
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    handshake: Option<Duration>,
+    option_idle: Option<Duration>,
+    transmission_idle: Option<Duration>,
+    request: Option<Duration>,
+    refused_handshake: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            option_idle: Some(DEFAULT_OPTION_IDLE_TIMEOUT),
+            transmission_idle: None,
+            request: None,
+            refused_handshake: REFUSED_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+// None if the future did not complete within the limit.
+async fn with_timeout<F: Future>(limit: Option<Duration>, fut: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+// Settings of the listener a connection came in on.
+#[derive(Debug, Clone, Copy)]
+struct ListenerConfig {
+    tls_mode: TlsMode,
+    negotiation: NegotiationMode,
+}
+
+impl ListenerConfig {
+    fn handshake_flags(&self) -> NbdHandshakeFlag {
+        match self.negotiation {
+            NegotiationMode::FixedNewstyle => NbdHandshakeFlag::FIXED_NEWSTYLE,
+            _ => NbdHandshakeFlag::empty(),
         }
     }
 }
 
 struct ServerShard {
     config: Arc<ServerConfig>,
+    listener: ListenerConfig,
     state: Arc<Mutex<ServerState>>,
-    image: Option<Image>,
-    tx_flags: NbdTxFlag,
+    image: Option<Arc<Image>>,
     client_flags: NbdClientFlag,
+    structured_reply: bool,
+    extended_headers: bool,
+    // Export the metadata contexts were selected for, and the selected ids.
+    meta_export: Option<String>,
+    meta_contexts: Vec<u32>,
+    identity: Option<ClientIdentity>,
+    shutdown: watch::Receiver<bool>,
+    // Refused connections are not counted, they are only told why.
+    connection: Result<ConnectionSlot, Refusal>,
+    export: Option<ExportSlot>,
+    // Calls into the image, including those whose request timed out.
+    backend_slots: Arc<Semaphore>,
 }
 
 impl ServerShard {
-    async fn handle_connection(mut self, mut sock: TcpStream) -> IoResult<()> {
+    // A client which is not through negotiation within the handshake timeout
+    // is disconnected, there is no way to tell it why.
+    async fn handle_connection(mut self, sock: Box<dyn Transport>) -> IoResult<()> {
+        let mut sock = MaybeTlsStream::Plain(sock);
+        let mut limit = self.config.timeouts.handshake;
+        // Refused connections hold no slot, they must not linger either.
+        if self.connection.is_err() {
+            let refused = self.config.timeouts.refused_handshake;
+            limit = Some(limit.map_or(refused, |limit| limit.min(refused)));
+        }
+        let negotiate = async {
+            if self.listener.negotiation == NegotiationMode::Oldstyle {
+                self.handle_oldstyle(&mut sock).await
+            } else {
+                self.negotiate(&mut sock).await
+            }
+        };
+        match with_timeout(limit, negotiate).await {
+            Some(Ok(true)) => self.transmission(sock).await,
+            Some(res) => res.map(|_| ()),
+            None => {
+                warn!(?limit, "handshake timeout");
+                Ok(())
+            }
+        }
+    }
+
+    // Whether the client made it to transmission.
+    async fn negotiate(&mut self, sock: &mut Connection) -> IoResult<bool> {
         // Handshake.
         sock.write_u64(INIT_PASSWD).await?;
         sock.write_u64(IHAVEOPT).await?;
-        sock.write_u16(self.config.handshake_flags).await?;
+        sock.write_u16(self.listener.handshake_flags().bits())
+            .await?;
         sock.flush().await?;
 
         let client_flags = NbdClientFlag::from_bits_retain(sock.read_u32().await?);
         info!(?client_flags, "read from client");
-        if !client_flags.contains(NbdClientFlag::FIXED_NEWSTYLE) {
-            error!("client do not support fixed newstyle negotiation");
-            return Err(std::io::ErrorKind::InvalidData.into());
+        let fixed = self.listener.negotiation == NegotiationMode::FixedNewstyle
+            && client_flags.contains(NbdClientFlag::FIXED_NEWSTYLE);
+        if !fixed {
+            info!("plain newstyle negotiation");
         }
         self.client_flags = client_flags;
         let config = self.config.clone();
 
-        // Handle options.
+        // Handle options. An idle client can not be told why it is dropped.
         loop {
-            let i_have_opt = sock.read_u64().await?;
+            let limit = config.timeouts.option_idle;
+            let Some(i_have_opt) = with_timeout(limit, sock.read_u64()).await else {
+                info!(?limit, "option phase idle timeout");
+                return Ok(false);
+            };
+            let i_have_opt = i_have_opt?;
             if i_have_opt != IHAVEOPT {
                 error!(?i_have_opt, "unknown magic number");
                 return Err(std::io::ErrorKind::InvalidData.into());
             }
             let option = sock.read_u32().await?;
-            let option: NbdOpt = FromPrimitive::from_u32(option).ok_or_else(|| {
-                error!(option, "unknown nbd option");
-                std::io::Error::from(std::io::ErrorKind::InvalidData)
-            })?;
-            info!(?option, "handle option");
-
-            let option_data_len = sock.read_u32().await?;
-            info!(?option_data_len, "option data len");
-            if option_data_len as usize > MAX_OPTION_DATA_LEN {
-                error!(option_data_len, "option data is too large");
-                return Err(std::io::ErrorKind::InvalidData.into());
+            let option_data_len = sock.read_u32().await? as usize;
+            debug!(option, option_data_len, "read option");
+            // Plain newstyle has no option replies.
+            if !fixed && option != NbdOpt::ExportName as u32 {
+                info!(option, "option refused without fixed newstyle");
+                return Ok(false);
             }
 
-            let mut option_data: Vec<u8> = Vec::with_capacity(option_data_len as usize);
-            option_data.resize(option_data_len as usize, 0);
-            sock.read_exact(&mut option_data).await?;
-
-            match config
-                .handle_option(&mut self, option, option_data, &mut sock)
+            match self
+                .read_option(&config, option, option_data_len, sock)
                 .await?
             {
                 OptionHandleState::Continue => continue,
-                OptionHandleState::End => break,
-                OptionHandleState::Abort => return Err(std::io::ErrorKind::InvalidData.into()),
+                OptionHandleState::End => return Ok(true),
+                OptionHandleState::Abort => {
+                    info!("negotiation ended without transmission");
+                    return Ok(false);
+                }
             }
         }
+    }
 
-        // Transmission.
-        loop {
-            // Handle request.
-            let req = Request::nbd_read(&mut sock).await?;
-            let trans_end = self.handle_request(req, &mut sock).await?;
-            if trans_end {
-                break;
+    // Read the data of an option and handle it.
+    async fn read_option(
+        &mut self,
+        config: &ServerConfig,
+        option: u32,
+        option_data_len: usize,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        // Skip the data of an option too large to stay in sync with the client.
+        let too_big = option_data_len > MAX_OPTION_DATA_LEN;
+        let mut option_data: Vec<u8> = Vec::new();
+        if too_big {
+            warn!(option, option_data_len, "option data is too large");
+            tokio::io::copy(
+                &mut (sock).take(option_data_len as u64),
+                &mut tokio::io::sink(),
+            )
+            .await?;
+        } else {
+            option_data.resize(option_data_len, 0);
+            sock.read_exact(&mut option_data).await?;
+        }
+
+        // The client is expected to give up with NBD_OPT_ABORT. Connections
+        // over the limits get one reply and are closed, so they can not pile
+        // up.
+        if let Some(refusal) = self.refusal().filter(|_| option != NbdOpt::Abort as u32) {
+            info!(option, ?refusal, "option refused");
+            let state = refuse_option(option, refusal.reply(), refusal.message(), sock).await?;
+            if self.connection.is_err() {
+                return Ok(OptionHandleState::Abort);
             }
+            return Ok(state);
         }
-        info!("transmission completed");
-        Ok(())
-    }
 
-    async fn handle_option(
-        &mut self,
-        opt: NbdOpt,
-        data: Vec<u8>,
-        sock: &mut TcpStream,
-    ) -> IoResult<bool> {
-        unimplemented!()
-    }
+        if too_big {
+            let msg = "option data is too large";
+            return refuse_option(option, NbdOptReply::ErrTooBig, msg, sock).await;
+        }
+        let Some(opt) = FromPrimitive::from_u32(option) else {
+            info!(option, "unknown nbd option");
+            let msg = format!("unknown option {}", option);
+            return refuse_option(option, NbdOptReply::ErrUnsup, &msg, sock).await;
+        };
+        info!(?opt, "handle option");
 
-    async fn handle_request(&mut self, req: Request, sock: &mut TcpStream) -> IoResult<bool> {
-        unimplemented!()
+        config.handle_option(self, opt, option_data, sock).await
     }
-}
 
-struct Request {
-    flags: u16,
-    cmd: NbdCmd,
-    cookie: u64,
-    offset: u64,
-    length: u32,
-    data: Vec<u8>,
-}
+    // Oldstyle clients get the default export right away. There is no way to
+    // refuse them but closing.
+    async fn handle_oldstyle(&mut self, sock: &mut Connection) -> IoResult<bool> {
+        let Some(name) = self.config.default_export.clone() else {
+            error!("no default export for oldstyle negotiation");
+            return Err(std::io::ErrorKind::NotFound.into());
+        };
+        if let Some(refusal) = self.refusal() {
+            info!(?refusal, "oldstyle client refused");
+            return Ok(false);
+        }
+        if self.admit_export(&name).is_err() {
+            return Ok(false);
+        }
+        let image = self.open_image(&name).await.map_err(|reply| {
+            error!(name, ?reply, "can not open default export");
+            IoError::from(IoErrorKind::NotFound)
+        })?;
+        if needs_block_size_negotiation(&name, &image) {
+            return Ok(false);
+        }
 
-impl NbdRead for Request {
-    async fn nbd_read(sock: &mut TcpStream) -> IoResult<Self> {
-        let request_magic = sock.read_u32().await?;
+        sock.write_u64(INIT_PASSWD).await?;
+        sock.write_u64(CLISERV_MAGIC).await?;
+        sock.write_u64(export_size(&image.info())).await?;
+        sock.write_u32(self.tx_flags(&image).bits() as u32).await?;
+        sock.write_all(&ZEROS[..124]).await?;
+        sock.flush().await?;
+        self.set_image(&name, image);
+        Ok(true)
+    }
+
+    // Requests are read one after the other, but handled concurrently. Replies
+    // are sent as soon as they are ready, the client matches them by cookie.
+    // On shutdown the requests in flight are finished and the image flushed,
+    // later requests fail with NBD_ESHUTDOWN until the client disconnects.
+    // An idle client is disconnected the same way, as if it sent
+    // NBD_CMD_DISC.
+    async fn transmission(self, sock: Connection) -> IoResult<()> {
+        let (mut reader, writer) = tokio::io::split(sock);
+        let writer = Arc::new(ReplyWriter::new(writer));
+        let inflight = Arc::new(Semaphore::new(self.config.max_inflight_requests));
+        let inflight_data = Arc::new(Semaphore::new(MAX_INFLIGHT_DATA_LEN));
+        let idle_limit = self.config.timeouts.transmission_idle;
+        let mut shutdown = self.shutdown.clone();
+        let mut draining = false;
+        let shard = Arc::new(self);
+        let mut tasks: JoinSet<IoResult<bool>> = JoinSet::new();
+
+        loop {
+            // Nothing is read while the limit of requests in flight is reached.
+            let permit = inflight.clone().acquire_owned().await.unwrap();
+            let read = async {
+                if shard.extended_headers {
+                    Ok(ExtendedRequest::nbd_read(&mut reader).await?.0)
+                } else {
+                    Request::nbd_read(&mut reader).await
+                }
+            };
+            tokio::pin!(read);
+            let idle = tokio::time::sleep(idle_limit.unwrap_or(Duration::MAX));
+            tokio::pin!(idle);
+            let req = loop {
+                tokio::select! {
+                    req = &mut read => break req?,
+                    _ = &mut idle, if idle_limit.is_some() => {
+                        while let Some(res) = tasks.try_join_next() {
+                            res??;
+                        }
+                        if tasks.is_empty() {
+                            info!(limit = ?idle_limit, "transmission idle timeout");
+                            shard.flush_image().await;
+                            return Ok(());
+                        }
+                        // Not idle while requests are in flight.
+                        idle.as_mut().reset(Instant::now() + idle_limit.unwrap());
+                    }
+                    _ = shutdown_requested(&mut shutdown), if !draining => {
+                        draining = true;
+                        info!(inflight = tasks.len(), "shutdown, finishing requests in flight");
+                        while let Some(res) = tasks.join_next().await {
+                            res??;
+                        }
+                        shard.flush_image().await;
+                    }
+                }
+            };
+
+            // Finish everything in flight before disconnecting.
+            if req.cmd == Some(NbdCmd::Disc) {
+                while let Some(res) = tasks.join_next().await {
+                    res??;
+                }
+                shard.handle_request(req, &writer).await?;
+                break;
+            }
+
+            // Payloads are at most MAX_REQUEST_DATA_LEN, so one always fits.
+            let data_permit = inflight_data
+                .clone()
+                .acquire_many_owned(req.data.len() as u32)
+                .await
+                .unwrap();
+            let task_shard = shard.clone();
+            let task_writer = writer.clone();
+            tasks.spawn(async move {
+                let _permit = (permit, data_permit);
+                task_shard.handle_request(req, &task_writer).await
+            });
+
+            // A failed reply means the connection is broken.
+            while let Some(res) = tasks.try_join_next() {
+                res??;
+            }
+        }
+        info!("transmission completed");
+        Ok(())
+    }
+
+    fn shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    fn refusal(&self) -> Option<Refusal> {
+        match &self.connection {
+            Err(refusal) => Some(*refusal),
+            Ok(_) if self.shutting_down() => Some(Refusal::ShuttingDown),
+            Ok(_) => None,
+        }
+    }
+
+    // Count the connection against the limit of the export it is about to
+    // use for transmission.
+    fn admit_export(&mut self, name: &str) -> Result<(), Refusal> {
+        let export = self.export_name(name).to_string();
+        self.state
+            .lock()
+            .unwrap()
+            .admit_export(&self.config.limits, &export)
+            .inspect_err(|refusal| info!(export, ?refusal, "export over limit"))?;
+        self.export = Some(ExportSlot {
+            state: self.state.clone(),
+            export,
+        });
+        Ok(())
+    }
+
+    async fn flush_image(&self) {
+        let Some(image) = &self.image else {
+            return;
+        };
+        if image.caps().contains(ImageCaps::FLUSH) {
+            if let Err(err) = image.flush().await {
+                error!(?err, "flush image");
+            }
+        }
+    }
+
+    fn access(&self, desc: &ImageDesc) -> Option<ExportAccess> {
+        self.config
+            .access
+            .access(self.identity.as_ref(), &desc.full_name())
+    }
+
+    // The default export goes by the empty name.
+    fn export_name<'a>(&'a self, name: &'a str) -> &'a str {
+        match &self.config.default_export {
+            Some(export) if name.is_empty() => export,
+            _ => name,
+        }
+    }
+
+    // Find an image the client is allowed to see.
+    fn find_image(&self, name: &str) -> Result<(Driver, ImageDesc, ExportAccess), NbdOptReply> {
+        let name = self.export_name(name);
+        let found = self.state.lock().unwrap().find_image(name);
+        let (drv, desc) = found.ok_or_else(|| {
+            info!(name, "image not found");
+            NbdOptReply::ErrUnknown
+        })?;
+        let access = self.access(&desc).ok_or_else(|| {
+            info!(name, identity = ?self.identity, "access denied");
+            NbdOptReply::ErrPolicy
+        })?;
+        Ok((drv, desc, access))
+    }
+
+    // Connections to the same export share the opened image, so that a flush
+    // on one of them covers writes from all of them.
+    async fn open_image(&self, name: &str) -> Result<Arc<Image>, NbdOptReply> {
+        let (drv, desc, access) = self.find_image(name)?;
+        let opened = self.state.lock().unwrap().opened_image(&desc);
+        let image = match opened {
+            Some(image) => image,
+            None => {
+                let image = drv.open(&desc).await.map_err(|err| {
+                    error!(?desc, ?err, "failed to open image");
+                    match err.kind() {
+                        IoErrorKind::PermissionDenied => NbdOptReply::ErrPolicy,
+                        _ => NbdOptReply::ErrUnknown,
+                    }
+                })?;
+                info!(?desc, info = ?image.info(), "open image");
+                self.state
+                    .lock()
+                    .unwrap()
+                    .insert_opened_image(&desc, Arc::new(image))
+            }
+        };
+        if access == ExportAccess::ReadOnly {
+            // Still backed by the shared handle.
+            return Ok(Arc::new((*image).clone().into_readonly()));
+        }
+        Ok(image)
+    }
+
+    // Forget everything negotiated so far, as required after NBD_OPT_STARTTLS.
+    fn reset_negotiation(&mut self) {
+        self.structured_reply = false;
+        self.extended_headers = false;
+        self.meta_export = None;
+        self.meta_contexts.clear();
+    }
+
+    // Transmission flags of an export, as far as the image and the negotiated
+    // options allow.
+    fn tx_flags(&self, image: &Image) -> NbdTxFlag {
+        let mut tx_flags = NbdTxFlag::HAS_FLAGS;
+        let info = image.info();
+        if info.readonly {
+            tx_flags |= NbdTxFlag::READ_ONLY;
+        }
+        if info.rotational {
+            tx_flags |= NbdTxFlag::SEND_ROTATIONAL;
+        }
+        let caps = image.caps();
+        for (cap, flag) in CAPS_TX_FLAGS {
+            if caps.contains(cap) {
+                tx_flags |= flag;
+            }
+        }
+        if self.extended_headers {
+            tx_flags |= NbdTxFlag::BLOCK_STATUS_PAYLOAD;
+        }
+        // Structured reads are sent in one chunk on request.
+        if self.structured_reply {
+            tx_flags |= NbdTxFlag::SEND_DF;
+        }
+        tx_flags
+    }
+
+    fn set_image(&mut self, name: &str, image: Arc<Image>) {
+        // Selected contexts only apply to the export they were selected for.
+        if self.meta_export.as_deref() != Some(name) {
+            self.meta_export = None;
+            self.meta_contexts.clear();
+        }
+        self.image = Some(image);
+    }
+
+    async fn handle_request(&self, req: Request, sock: &ReplyWriter) -> IoResult<bool> {
+        let image = self.image.as_ref().ok_or_else(|| {
+            error!("no image opened for transmission");
+            IoError::from(IoErrorKind::InvalidData)
+        })?;
+        let info = image.info();
+        let size = export_size(&info);
+        let in_range = req
+            .offset
+            .checked_add(req.length)
+            .is_some_and(|end| end <= size);
+        let block_size = block_size(&info);
+        let aligned = block_size.is_aligned(req.offset) && block_size.is_aligned(req.length);
+
+        let caps = image.caps();
+        let deadline = self
+            .config
+            .timeouts
+            .request
+            .map(|limit| Instant::now() + limit);
+        // The header has been read in full, so the session stays in sync.
+        let Some(cmd) = req.cmd else {
+            debug!(cookie = req.cookie, "unknown command");
+            self.send_result(&req, Err(NbdError::Inval), sock).await?;
+            return Ok(false);
+        };
+        let res = match cmd {
+            NbdCmd::Disc => {
+                // No reply for NBD_CMD_DISC, just make sure everything is on disk.
+                info!("client requested disconnect");
+                self.flush_image().await;
+                return Ok(true);
+            }
+            cmd if self.shutting_down() => {
+                debug!(?cmd, "request during shutdown");
+                Err(NbdError::Shutdown)
+            }
+            cmd if info.readonly && modifies_image(cmd) => {
+                debug!(?cmd, "write to read-only export");
+                Err(NbdError::Perm)
+            }
+            cmd if !caps.contains(required_caps(cmd)) => {
+                debug!(?cmd, ?caps, "command not supported by image");
+                Err(NbdError::Inval)
+            }
+            cmd if !caps.contains(required_flag_caps(cmd, req.flags)) => {
+                debug!(?cmd, flags = ?req.flags, ?caps, "flags not supported by image");
+                Err(NbdError::Inval)
+            }
+            cmd if !aligned && !matches!(cmd, NbdCmd::Flush | NbdCmd::Resize) => {
+                debug!(
+                    ?cmd,
+                    offset = req.offset,
+                    length = req.length,
+                    ?block_size,
+                    "unaligned request"
+                );
+                Err(NbdError::Inval)
+            }
+            NbdCmd::Read => {
+                if !in_range || req.length > block_size.maximum as u64 {
+                    Err(NbdError::Inval)
+                } else if self.structured_reply {
+                    self.handle_structured_read(&req, deadline, sock).await?;
+                    return Ok(false);
+                } else {
+                    let (offset, length) = (req.offset, req.length as usize);
+                    let read =
+                        move |image: Arc<Image>| async move { image.read(offset, length).await };
+                    match self.backend(&req, deadline, read).await {
+                        Ok(data) => {
+                            let reply = SimpleReply {
+                                error: None,
+                                cookie: req.cookie,
+                                data,
+                            };
+                            reply.nbd_write(&mut *sock.lock().await).await?;
+                            return Ok(false);
+                        }
+                        Err(err) => Err(io_error_to_nbd(&req, err)),
+                    }
+                }
+            }
+            NbdCmd::Write => {
+                if req.length > block_size.maximum as u64 {
+                    Err(NbdError::Inval)
+                } else if !in_range {
+                    Err(NbdError::NoSpc)
+                } else {
+                    let (offset, data, flags) = (req.offset, req.data.clone(), req.write_flags());
+                    let write = move |image: Arc<Image>| async move {
+                        image.write(offset, data, flags).await
+                    };
+                    self.backend(&req, deadline, write)
+                        .await
+                        .map_err(|err| io_error_to_nbd(&req, err))
+                }
+            }
+            NbdCmd::Flush => self
+                .backend(&req, deadline, |image| async move { image.flush().await })
+                .await
+                .map_err(|err| io_error_to_nbd(&req, err)),
+            NbdCmd::Trim => {
+                if !in_range {
+                    Err(NbdError::Inval)
+                } else {
+                    let (offset, length, flags) = (req.offset, req.length, req.write_flags());
+                    let trim = move |image: Arc<Image>| async move {
+                        image.trim(offset, length, flags).await
+                    };
+                    self.backend(&req, deadline, trim)
+                        .await
+                        .map_err(|err| io_error_to_nbd(&req, err))
+                }
+            }
+            NbdCmd::WriteZeroes => {
+                if !in_range {
+                    Err(NbdError::NoSpc)
+                } else {
+                    let (offset, length, flags) = (req.offset, req.length, req.write_flags());
+                    let write_zeroes = move |image: Arc<Image>| async move {
+                        image.write_zeroes(offset, length, flags).await
+                    };
+                    match self.backend(&req, deadline, write_zeroes).await {
+                        // The client falls back to writing zeros itself.
+                        Err(err)
+                            if flags.contains(WriteFlags::FAST_ZERO)
+                                && err.kind() == IoErrorKind::Unsupported =>
+                        {
+                            debug!(offset = req.offset, "fast zero not possible");
+                            Err(NbdError::NotSup)
+                        }
+                        res => res.map_err(|err| io_error_to_nbd(&req, err)),
+                    }
+                }
+            }
+            // The length is the new size, the offset must be zero. Without
+            // extended headers sizes are limited to the 32 bit length.
+            NbdCmd::Resize => {
+                if req.offset != 0 || !block_size.is_aligned(req.length) {
+                    debug!(
+                        offset = req.offset,
+                        new_size = req.length,
+                        ?block_size,
+                        "invalid resize request"
+                    );
+                    Err(NbdError::Inval)
+                } else {
+                    info!(size, new_size = req.length, "resize image");
+                    let new_size = req.length;
+                    let resize =
+                        move |image: Arc<Image>| async move { image.resize(new_size).await };
+                    self.backend(&req, deadline, resize)
+                        .await
+                        .map_err(|err| io_error_to_nbd(&req, err))
+                }
+            }
+            NbdCmd::Cache => {
+                if !in_range {
+                    Err(NbdError::Inval)
+                } else {
+                    let (offset, length) = (req.offset, req.length);
+                    let cache =
+                        move |image: Arc<Image>| async move { image.cache(offset, length).await };
+                    self.backend(&req, deadline, cache)
+                        .await
+                        .map_err(|err| io_error_to_nbd(&req, err))
+                }
+            }
+            NbdCmd::BlockStatus => {
+                if !in_range || req.length == 0 || self.meta_contexts.is_empty() {
+                    Err(NbdError::Inval)
+                } else {
+                    self.handle_block_status(&req, deadline, sock).await?;
+                    return Ok(false);
+                }
+            }
+        };
+
+        self.send_result(&req, res, sock).await?;
+        Ok(false)
+    }
+
+    // Bound the time the image takes for a request, the deadline covers every
+    // call made for it. The request fails with NBD_EIO once it passed, but the
+    // call runs to completion and holds one of the backend slots until then,
+    // so calls stuck in the image do not pile up.
+    async fn backend<T, F>(
+        &self,
+        req: &Request,
+        deadline: Option<Instant>,
+        op: impl FnOnce(Arc<Image>) -> F,
+    ) -> IoResult<T>
+    where
+        F: Future<Output = IoResult<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let image = self
+            .image
+            .clone()
+            .ok_or_else(|| IoError::from(IoErrorKind::InvalidData))?;
+        let Some(deadline) = deadline else {
+            return op(image).await;
+        };
+        let call = async {
+            let slot = self.backend_slots.clone().acquire_owned().await.unwrap();
+            let fut = op(image);
+            tokio::spawn(async move {
+                let _slot = slot;
+                fut.await
+            })
+            .await
+        };
+        match tokio::time::timeout_at(deadline, call).await {
+            Ok(res) => res?,
+            Err(_) => {
+                warn!(
+                    cmd = ?req.cmd,
+                    cookie = req.cookie,
+                    offset = req.offset,
+                    length = req.length,
+                    "request timeout"
+                );
+                Err(IoErrorKind::TimedOut.into())
+            }
+        }
+    }
+
+    // Send a reply without payload, in whatever form has been negotiated.
+    async fn send_result(
+        &self,
+        req: &Request,
+        res: Result<(), NbdError>,
+        sock: &ReplyWriter,
+    ) -> IoResult<()> {
+        if self.structured_reply {
+            let reply = match res {
+                Ok(()) => StructuredReply::none(req.cookie),
+                Err(err) => StructuredReply::error(req.cookie, err, ""),
+            };
+            self.send_chunk(req, reply.done(), sock).await
+        } else {
+            let reply = SimpleReply {
+                error: res.err(),
+                cookie: req.cookie,
+                data: Bytes::new(),
+            };
+            reply.nbd_write(&mut *sock.lock().await).await
+        }
+    }
+
+    async fn send_chunk(
+        &self,
+        req: &Request,
+        reply: StructuredReply,
+        sock: &ReplyWriter,
+    ) -> IoResult<()> {
+        if self.extended_headers {
+            ExtendedReply {
+                offset: req.offset,
+                reply,
+            }
+            .nbd_write(&mut *sock.lock().await)
+            .await
+        } else {
+            reply.nbd_write(&mut *sock.lock().await).await
+        }
+    }
+
+    // One block status chunk for each selected context, the last one is marked
+    // as done. Contexts are queried before anything is sent, so a failure can
+    // still be reported as a single error.
+    async fn handle_block_status(
+        &self,
+        req: &Request,
+        deadline: Option<Instant>,
+        sock: &ReplyWriter,
+    ) -> IoResult<()> {
+        // With NBD_CMD_FLAG_PAYLOAD_LEN the client picks a subset of the
+        // selected contexts.
+        let ids: Vec<u32> = if req.flags.contains(NbdCmdFlag::PAYLOAD_LEN) {
+            let ids: Vec<u32> = req
+                .data
+                .chunks_exact(4)
+                .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+                .collect();
+            if ids.is_empty() || ids.iter().any(|id| !self.meta_contexts.contains(id)) {
+                debug!(?ids, "invalid context ids in block status payload");
+                return self.send_result(req, Err(NbdError::Inval), sock).await;
+            }
+            ids
+        } else {
+            self.meta_contexts.clone()
+        };
+
+        let mut replies = Vec::with_capacity(ids.len());
+        for id in ids {
+            if self.config.meta_context(id).is_none() {
+                continue;
+            }
+            let (config, offset, length) = (self.config.clone(), req.offset, req.length);
+            let block_status = move |image: Arc<Image>| async move {
+                let context = config.meta_context(id).unwrap();
+                context.block_status(&image, offset, length).await
+            };
+            match self.backend(req, deadline, block_status).await {
+                Ok(mut extents) => {
+                    if req.flags.contains(NbdCmdFlag::REQ_ONE) {
+                        extents.truncate(1);
+                    } else {
+                        extents.truncate(MAX_BLOCK_STATUS_EXTENTS);
+                    }
+                    let reply = if self.extended_headers {
+                        StructuredReply::block_status_ext(req.cookie, id, &extents)
+                    } else {
+                        StructuredReply::block_status(req.cookie, id, &extents)
+                    };
+                    replies.push(reply);
+                }
+                Err(err) => {
+                    let err = io_error_to_nbd(req, err);
+                    return self.send_result(req, Err(err), sock).await;
+                }
+            }
+        }
+
+        let last = replies.len() - 1;
+        for (i, reply) in replies.into_iter().enumerate() {
+            if i == last {
+                self.send_chunk(req, reply.done(), sock).await?;
+            } else {
+                self.send_chunk(req, reply, sock).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Read in chunks, so that zeroed ranges can be sent as holes and a failure
+    // can be reported with its offset. With NBD_CMD_FLAG_DF everything goes in
+    // a single chunk.
+    async fn handle_structured_read(
+        &self,
+        req: &Request,
+        deadline: Option<Instant>,
+        sock: &ReplyWriter,
+    ) -> IoResult<()> {
+        let end = req.offset + req.length;
+        let mut offset = req.offset;
+        if offset == end {
+            return self
+                .send_chunk(req, StructuredReply::none(req.cookie).done(), sock)
+                .await;
+        }
+
+        let chunk_len = if req.flags.contains(NbdCmdFlag::DF) {
+            req.length
+        } else {
+            READ_CHUNK_LEN as u64
+        };
+        while offset < end {
+            let len = (end - offset).min(chunk_len);
+            let read =
+                move |image: Arc<Image>| async move { image.read(offset, len as usize).await };
+            let reply = match self.backend(req, deadline, read).await {
+                Ok(data) if data.iter().all(|b| *b == 0) => {
+                    StructuredReply::offset_hole(req.cookie, offset, len as u32)
+                }
+                Ok(data) => StructuredReply::offset_data(req.cookie, offset, &data),
+                Err(err) => {
+                    let err = io_error_to_nbd(req, err);
+                    let reply = StructuredReply::error_offset(req.cookie, err, offset);
+                    return self.send_chunk(req, reply.done(), sock).await;
+                }
+            };
+            offset += len;
+            if offset == end {
+                self.send_chunk(req, reply.done(), sock).await?;
+            } else {
+                self.send_chunk(req, reply, sock).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn required_caps(cmd: NbdCmd) -> ImageCaps {
+    match cmd {
+        NbdCmd::Read => ImageCaps::READ,
+        NbdCmd::Write => ImageCaps::WRITE,
+        NbdCmd::Flush => ImageCaps::FLUSH,
+        NbdCmd::Trim => ImageCaps::TRIM,
+        NbdCmd::WriteZeroes => ImageCaps::WRITE_ZEROES,
+        NbdCmd::Cache => ImageCaps::CACHE,
+        NbdCmd::Resize => ImageCaps::RESIZE,
+        _ => ImageCaps::empty(),
+    }
+}
+
+fn modifies_image(cmd: NbdCmd) -> bool {
+    modifies_data(cmd) || cmd == NbdCmd::Resize
+}
+
+fn modifies_data(cmd: NbdCmd) -> bool {
+    matches!(cmd, NbdCmd::Write | NbdCmd::Trim | NbdCmd::WriteZeroes)
+}
+
+// Block size constraints of an image, limited by what the server accepts.
+fn block_size(info: &ImageInfo) -> BlockSize {
+    let minimum = info.block_size.minimum.max(1);
+    BlockSize {
+        minimum,
+        preferred: info.block_size.preferred.max(minimum),
+        maximum: info
+            .block_size
+            .maximum
+            .min(MAX_REQUEST_DATA_LEN as u32)
+            .max(minimum),
+    }
+}
+
+// The tail of an image past its last full block can not be reached with
+// aligned requests, so it is left out of the export.
+fn export_size(info: &ImageInfo) -> u64 {
+    let minimum = block_size(info).minimum as u64;
+    info.size as u64 / minimum * minimum
+}
+
+// Without NBD_OPT_GO the client has no way to learn the constraints, so such
+// exports can only be served if requests need no alignment.
+fn needs_block_size_negotiation(name: &str, image: &Image) -> bool {
+    let needs_alignment = block_size(&image.info()).needs_alignment();
+    if needs_alignment {
+        info!(name, "export needs block size negotiation");
+    }
+    needs_alignment
+}
+
+// Capabilities needed to honor the command flags. Flags that have no meaning
+// for the command are ignored, clients may send NBD_CMD_FLAG_FUA with any of
+// them.
+fn required_flag_caps(cmd: NbdCmd, flags: NbdCmdFlag) -> ImageCaps {
+    let mut caps = ImageCaps::empty();
+    if flags.contains(NbdCmdFlag::FUA) && modifies_data(cmd) {
+        caps |= ImageCaps::FUA;
+    }
+    if flags.contains(NbdCmdFlag::FAST_ZERO) && cmd == NbdCmd::WriteZeroes {
+        caps |= ImageCaps::FAST_ZERO;
+    }
+    caps
+}
+
+// NBD_ENOTSUP and NBD_EOVERFLOW only have a meaning for a fast zero and a read
+// with NBD_CMD_FLAG_DF respectively, clients may not expect them otherwise.
+fn io_error_to_nbd(req: &Request, err: IoError) -> NbdError {
+    let nbd_err = match NbdError::from(&err) {
+        NbdError::NotSup
+            if req.cmd != Some(NbdCmd::WriteZeroes)
+                || !req.flags.contains(NbdCmdFlag::FAST_ZERO) =>
+        {
+            NbdError::Inval
+        }
+        NbdError::Overflow
+            if req.cmd != Some(NbdCmd::Read) || !req.flags.contains(NbdCmdFlag::DF) =>
+        {
+            NbdError::Inval
+        }
+        nbd_err => nbd_err,
+    };
+    error!(cmd = ?req.cmd, ?err, ?nbd_err, "request failed");
+    nbd_err
+}
+
+struct Request {
+    flags: NbdCmdFlag,
+    // None for a command we do not know, it is answered with NBD_EINVAL.
+    cmd: Option<NbdCmd>,
+    cookie: u64,
+    offset: u64,
+    length: u64,
+    data: Bytes,
+}
+
+impl Request {
+    fn new(flags: u16, cmd: u16, cookie: u64, offset: u64, length: u64) -> Self {
+        debug!(flags, cmd, cookie, offset, length, "read request");
+        Request {
+            flags: NbdCmdFlag::from_bits_retain(flags),
+            cmd: FromPrimitive::from_u16(cmd),
+            cookie,
+            offset,
+            length,
+            data: Bytes::new(),
+        }
+    }
+
+    fn write_flags(&self) -> WriteFlags {
+        let mut flags = WriteFlags::empty();
+        if self.flags.contains(NbdCmdFlag::FUA) {
+            flags |= WriteFlags::FUA;
+        }
+        if self.flags.contains(NbdCmdFlag::NO_HOLE) {
+            flags |= WriteFlags::NO_HOLE;
+        }
+        if self.flags.contains(NbdCmdFlag::FAST_ZERO) && self.cmd == Some(NbdCmd::WriteZeroes) {
+            flags |= WriteFlags::FAST_ZERO;
+        }
+        flags
+    }
+
+    async fn read_payload<R: AsyncRead + Unpin + Send>(
+        &mut self,
+        sock: &mut R,
+        length: u64,
+    ) -> IoResult<()> {
+        if length > MAX_REQUEST_DATA_LEN as u64 {
+            error!(length, "request payload is too large");
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        let mut buf = BytesMut::zeroed(length as usize);
+        sock.read_exact(&mut buf).await?;
+        self.data = buf.freeze();
+        Ok(())
+    }
+}
+
+impl NbdRead for Request {
+    async fn nbd_read<R: AsyncRead + Unpin + Send>(sock: &mut R) -> IoResult<Self> {
+        let request_magic = sock.read_u32().await?;
         if request_magic != NBD_REQUEST_MAGIC {
             error!(?request_magic, "request magic mismatch");
             return Err(std::io::ErrorKind::InvalidData.into());
@@ -287,136 +1825,362 @@ impl NbdRead for Request {
         let cmd = sock.read_u16().await?;
         let cookie = sock.read_u64().await?;
         let offset = sock.read_u64().await?;
-        let length = sock.read_u32().await?;
-        debug!(flags, cmd, cookie, offset, length, "read request");
+        let length = sock.read_u32().await? as u64;
+
+        let mut req = Request::new(flags, cmd, cookie, offset, length);
+        if req.cmd == Some(NbdCmd::Write) {
+            req.read_payload(sock, length).await?;
+        }
+        Ok(req)
+    }
+}
 
-        let cmd: NbdCmd =
-            FromPrimitive::from_u16(cmd).ok_or(std::io::Error::from(ErrorKind::InvalidData))?;
-        let mut data: Vec<u8> = Vec::new();
+// Request with extended header, the length is 64 bit and describes the payload
+// when there is one.
+struct ExtendedRequest(Request);
 
-        if cmd == NbdCmd::Write {
-            data.reserve(length as usize);
-            unsafe { data.set_len(length as usize) };
-            sock.read_exact(&mut data).await?;
+impl NbdRead for ExtendedRequest {
+    async fn nbd_read<R: AsyncRead + Unpin + Send>(sock: &mut R) -> IoResult<Self> {
+        let request_magic = sock.read_u32().await?;
+        if request_magic != NBD_EXTENDED_REQUEST_MAGIC {
+            error!(?request_magic, "extended request magic mismatch");
+            return Err(std::io::ErrorKind::InvalidData.into());
         }
 
-        Ok(Request {
-            flags,
-            cmd,
-            cookie,
-            offset,
-            length,
-            data,
-        })
+        let flags = sock.read_u16().await?;
+        let cmd = sock.read_u16().await?;
+        let cookie = sock.read_u64().await?;
+        let offset = sock.read_u64().await?;
+        let length = sock.read_u64().await?;
+
+        let mut req = Request::new(flags, cmd, cookie, offset, length);
+        let payload_len = NbdCmdFlag::from_bits_retain(flags).contains(NbdCmdFlag::PAYLOAD_LEN);
+        if req.cmd == Some(NbdCmd::Write) {
+            req.read_payload(sock, length).await?;
+        } else if req.cmd == Some(NbdCmd::BlockStatus) && payload_len {
+            // The payload carries the effect length, followed by context ids.
+            req.read_payload(sock, length).await?;
+            if req.data.len() < 8 || !req.data.len().is_multiple_of(4) {
+                error!(length, "malformed block status payload");
+                return Err(std::io::ErrorKind::InvalidData.into());
+            }
+            req.length = req.data.get_u64();
+        } else if payload_len {
+            // Payload of a command that does not take one, skip it.
+            req.read_payload(sock, length).await?;
+            req.data = Bytes::new();
+        }
+        Ok(ExtendedRequest(req))
     }
 }
 
 struct OptReply {
-    option: NbdOpt,
+    // Raw option number, unknown options are answered as well.
+    option: u32,
     reply: NbdOptReply,
     data: Vec<u8>,
 }
 
 impl NbdWrite for OptReply {
-    async fn nbd_write(&self, sock: &mut TcpStream) -> IoResult<()> {
+    async fn nbd_write<W: AsyncWrite + Unpin + Send>(&self, sock: &mut W) -> IoResult<()> {
         sock.write_u64(proto::NBD_OPT_REPLY_MAGIC).await?;
-        sock.write_u32(self.option as u32).await?;
+        sock.write_u32(self.option).await?;
         sock.write_i32(self.reply as i32).await?;
         sock.write_u32(self.data.len().try_into().unwrap()).await?;
-        if self.data.len() != 0 {
+        if !self.data.is_empty() {
             sock.write_all(&self.data).await?;
         }
         Ok(())
     }
 }
 
-struct ExportNameOptReply {
-    size: u64,
-    tx_flags: NbdTxFlag,
-    no_zeros: bool,
+struct SimpleReply {
+    error: Option<NbdError>,
+    cookie: u64,
+    data: Bytes,
 }
 
-impl NbdWrite for ExportNameOptReply {
-    async fn nbd_write(&self, sock: &mut TcpStream) -> IoResult<()> {
-        sock.write_u64(self.size).await?;
-        sock.write_u16(self.tx_flags.bits()).await?;
-        if !self.no_zeros {
-            sock.write_all(&ZEROS[..124]).await?;
+impl NbdWrite for SimpleReply {
+    async fn nbd_write<W: AsyncWrite + Unpin + Send>(&self, sock: &mut W) -> IoResult<()> {
+        let mut header = BytesMut::with_capacity(16);
+        header.put_u32(NBD_SIMPLE_REPLY_MAGIC);
+        header.put_u32(self.error.map_or(0, |err| err as u32));
+        header.put_u64(self.cookie);
+        sock.write_all(&header).await?;
+        if !self.data.is_empty() {
+            sock.write_all(&self.data).await?;
         }
+        sock.flush().await?;
         Ok(())
     }
 }
 
-enum OptionHandleState {
-    Continue,
-    End,
-    Abort,
+struct StructuredReply {
+    flags: NbdReplyFlag,
+    ty: NbdReplyType,
+    cookie: u64,
+    payload: Bytes,
 }
 
-#[async_trait]
-trait OptionHandler: Send + Sync {
-    async fn handle_option(
-        &self,
-        server_shard: &mut ServerShard,
-        opt: NbdOpt,
-        data: Vec<u8>,
-        sock: &mut TcpStream,
-    ) -> IoResult<OptionHandleState>;
-}
+impl StructuredReply {
+    fn new(ty: NbdReplyType, cookie: u64, payload: Bytes) -> Self {
+        StructuredReply {
+            flags: NbdReplyFlag::empty(),
+            ty,
+            cookie,
+            payload,
+        }
+    }
 
-#[derive(Debug, Default)]
-struct UnknownOptionHandler {}
+    fn none(cookie: u64) -> Self {
+        Self::new(NbdReplyType::None, cookie, Bytes::new())
+    }
 
-#[async_trait]
-impl OptionHandler for UnknownOptionHandler {
-    async fn handle_option(
-        &self,
-        _server_shard: &mut ServerShard,
-        opt: NbdOpt,
-        _data: Vec<u8>,
-        sock: &mut TcpStream,
-    ) -> IoResult<OptionHandleState> {
-        let reply = OptReply {
-            option: opt,
-            reply: NbdOptReply::ErrUnsup,
-            data: format!("unknown option {}", opt as i32).into_bytes(),
-        };
-        reply.nbd_write(sock).await?;
-        Ok(OptionHandleState::Continue)
+    fn offset_data(cookie: u64, offset: u64, data: &[u8]) -> Self {
+        let mut payload = BytesMut::with_capacity(8 + data.len());
+        payload.put_u64(offset);
+        payload.put_slice(data);
+        Self::new(NbdReplyType::OffsetData, cookie, payload.freeze())
     }
-}
 
-// NBD_OPT_EXPORT_NAME (1)
-#[derive(Debug, Default)]
-struct ExportNameOptionHandler {}
+    fn offset_hole(cookie: u64, offset: u64, length: u32) -> Self {
+        let mut payload = BytesMut::with_capacity(12);
+        payload.put_u64(offset);
+        payload.put_u32(length);
+        Self::new(NbdReplyType::OffsetHole, cookie, payload.freeze())
+    }
 
-#[async_trait]
-impl OptionHandler for ExportNameOptionHandler {
-    async fn handle_option(
-        &self,
-        server_shard: &mut ServerShard,
-        _opt: NbdOpt,
-        data: Vec<u8>,
-        sock: &mut TcpStream,
-    ) -> IoResult<OptionHandleState> {
-        let image_name = String::from_utf8(data)
-            .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
-        let (drv, desc) = server_shard
-            .state
-            .lock()
-            .unwrap()
-            .find_image(&image_name)
-            .ok_or(IoError::from(IoErrorKind::InvalidData))?;
-        let image = drv.open(&desc).await?;
-        let info = image.info();
-        info!(?desc, ?info, "open image");
-        server_shard.image = Some(image);
+    fn error(cookie: u64, err: NbdError, msg: &str) -> Self {
+        let mut payload = BytesMut::with_capacity(6 + msg.len());
+        payload.put_u32(err as u32);
+        payload.put_u16(msg.len() as u16);
+        payload.put_slice(msg.as_bytes());
+        Self::new(NbdReplyType::Error, cookie, payload.freeze())
+    }
+
+    fn error_offset(cookie: u64, err: NbdError, offset: u64) -> Self {
+        let mut payload = BytesMut::with_capacity(14);
+        payload.put_u32(err as u32);
+        payload.put_u16(0);
+        payload.put_u64(offset);
+        Self::new(NbdReplyType::ErrorOffset, cookie, payload.freeze())
+    }
+
+    fn block_status(cookie: u64, id: u32, extents: &[(u64, u32)]) -> Self {
+        let mut payload = BytesMut::with_capacity(4 + extents.len() * 8);
+        payload.put_u32(id);
+        for (length, flags) in extents {
+            // Never more than the requested length, which fits.
+            payload.put_u32(*length as u32);
+            payload.put_u32(*flags);
+        }
+        Self::new(NbdReplyType::BlockStatus, cookie, payload.freeze())
+    }
+
+    fn block_status_ext(cookie: u64, id: u32, extents: &[(u64, u32)]) -> Self {
+        let mut payload = BytesMut::with_capacity(8 + extents.len() * 16);
+        payload.put_u32(id);
+        payload.put_u32(extents.len() as u32);
+        for (length, flags) in extents {
+            payload.put_u64(*length);
+            payload.put_u64(*flags as u64);
+        }
+        Self::new(NbdReplyType::BlockStatusExt, cookie, payload.freeze())
+    }
+
+    fn done(self) -> Self {
+        StructuredReply {
+            flags: self.flags | NbdReplyFlag::DONE,
+            ..self
+        }
+    }
+}
+
+impl NbdWrite for StructuredReply {
+    async fn nbd_write<W: AsyncWrite + Unpin + Send>(&self, sock: &mut W) -> IoResult<()> {
+        let mut header = BytesMut::with_capacity(20);
+        header.put_u32(NBD_STRUCTURED_REPLY_MAGIC);
+        header.put_u16(self.flags.bits());
+        header.put_u16(self.ty as u16);
+        header.put_u64(self.cookie);
+        header.put_u32(self.payload.len() as u32);
+        sock.write_all(&header).await?;
+        if !self.payload.is_empty() {
+            sock.write_all(&self.payload).await?;
+        }
+        sock.flush().await?;
+        Ok(())
+    }
+}
+
+// Structured reply chunk with an extended header.
+struct ExtendedReply {
+    offset: u64,
+    reply: StructuredReply,
+}
+
+impl NbdWrite for ExtendedReply {
+    async fn nbd_write<W: AsyncWrite + Unpin + Send>(&self, sock: &mut W) -> IoResult<()> {
+        let reply = &self.reply;
+        let mut header = BytesMut::with_capacity(32);
+        header.put_u32(NBD_EXTENDED_REPLY_MAGIC);
+        header.put_u16(reply.flags.bits());
+        header.put_u16(reply.ty as u16);
+        header.put_u64(reply.cookie);
+        header.put_u64(self.offset);
+        header.put_u64(reply.payload.len() as u64);
+        sock.write_all(&header).await?;
+        if !reply.payload.is_empty() {
+            sock.write_all(&reply.payload).await?;
+        }
+        sock.flush().await?;
+        Ok(())
+    }
+}
+
+impl OptReply {
+    fn ack(option: NbdOpt) -> Self {
+        OptReply {
+            option: option as u32,
+            reply: NbdOptReply::Ack,
+            data: Vec::new(),
+        }
+    }
+
+    fn error(option: NbdOpt, reply: NbdOptReply, msg: &str) -> Self {
+        OptReply {
+            option: option as u32,
+            reply,
+            data: msg.as_bytes().to_vec(),
+        }
+    }
+
+    fn info(option: NbdOpt, info: NbdInfo, payload: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(2 + payload.len());
+        data.put_u16(info as u16);
+        data.put_slice(payload);
+        OptReply {
+            option: option as u32,
+            reply: NbdOptReply::Info,
+            data,
+        }
+    }
+}
+
+struct ExportNameOptReply {
+    size: u64,
+    tx_flags: NbdTxFlag,
+    no_zeros: bool,
+}
+
+impl NbdWrite for ExportNameOptReply {
+    async fn nbd_write<W: AsyncWrite + Unpin + Send>(&self, sock: &mut W) -> IoResult<()> {
+        sock.write_u64(self.size).await?;
+        sock.write_u16(self.tx_flags.bits()).await?;
+        if !self.no_zeros {
+            sock.write_all(&ZEROS[..124]).await?;
+        }
+        Ok(())
+    }
+}
+
+// Reply to an option with an error. There is no way to report errors for
+// NBD_OPT_EXPORT_NAME, the connection is closed instead.
+async fn refuse_option(
+    option: u32,
+    reply: NbdOptReply,
+    msg: &str,
+    sock: &mut Connection,
+) -> IoResult<OptionHandleState> {
+    if option == NbdOpt::ExportName as u32 {
+        info!(?reply, msg, "close on refused NBD_OPT_EXPORT_NAME");
+        return Ok(OptionHandleState::Abort);
+    }
+    OptReply {
+        option,
+        reply,
+        data: msg.as_bytes().to_vec(),
+    }
+    .nbd_write(sock)
+    .await?;
+    Ok(OptionHandleState::Continue)
+}
+
+enum OptionHandleState {
+    Continue,
+    End,
+    Abort,
+}
+
+#[async_trait]
+trait OptionHandler: Send + Sync {
+    async fn handle_option(
+        &self,
+        server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState>;
+}
+
+#[derive(Debug, Default)]
+struct UnknownOptionHandler {}
+
+#[async_trait]
+impl OptionHandler for UnknownOptionHandler {
+    async fn handle_option(
+        &self,
+        _server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        _data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        OptReply::error(
+            opt,
+            NbdOptReply::ErrUnsup,
+            &format!("unsupported option {:?}", opt),
+        )
+        .nbd_write(sock)
+        .await?;
+        Ok(OptionHandleState::Continue)
+    }
+}
+
+// NBD_OPT_EXPORT_NAME (1)
+#[derive(Debug, Default)]
+struct ExportNameOptionHandler {}
 
+#[async_trait]
+impl OptionHandler for ExportNameOptionHandler {
+    async fn handle_option(
+        &self,
+        server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        let option = opt as u32;
+        let Ok(image_name) = String::from_utf8(data) else {
+            return refuse_option(option, NbdOptReply::ErrInvalid, "invalid export name", sock)
+                .await;
+        };
+        let image = match server_shard.open_image(&image_name).await {
+            Ok(image) => image,
+            Err(reply) => return refuse_option(option, reply, "can not open image", sock).await,
+        };
+        if needs_block_size_negotiation(&image_name, &image) {
+            let msg = "export needs block size negotiation";
+            return refuse_option(option, NbdOptReply::ErrBlockSizeReqd, msg, sock).await;
+        }
+        if let Err(refusal) = server_shard.admit_export(&image_name) {
+            return refuse_option(option, refusal.reply(), refusal.message(), sock).await;
+        }
         let reply = ExportNameOptReply {
-            size: info.size as u64,
-            tx_flags: server_shard.tx_flags,
+            size: export_size(&image.info()),
+            tx_flags: server_shard.tx_flags(&image),
             no_zeros: server_shard.client_flags.contains(NbdClientFlag::NO_ZEROES),
         };
+        server_shard.set_image(&image_name, image);
         reply.nbd_write(sock).await?;
 
         Ok(OptionHandleState::End)
@@ -434,14 +2198,9 @@ impl OptionHandler for AbortOptionHandler {
         _server_shard: &mut ServerShard,
         opt: NbdOpt,
         _data: Vec<u8>,
-        sock: &mut TcpStream,
+        sock: &mut Connection,
     ) -> IoResult<OptionHandleState> {
-        let reply = OptReply {
-            option: opt,
-            reply: NbdOptReply::Ack,
-            data: Vec::new(),
-        };
-        reply.nbd_write(sock).await?;
+        OptReply::ack(opt).nbd_write(sock).await?;
         Ok(OptionHandleState::Abort)
     }
 }
@@ -456,29 +2215,1939 @@ impl OptionHandler for ListOptionHandler {
         &self,
         server_shard: &mut ServerShard,
         opt: NbdOpt,
-        _data: Vec<u8>,
-        sock: &mut TcpStream,
+        data: Vec<u8>,
+        sock: &mut Connection,
     ) -> IoResult<OptionHandleState> {
-        let images = server_shard.state.lock().unwrap().list_images_full_name();
+        if !data.is_empty() {
+            OptReply::error(opt, NbdOptReply::ErrInvalid, "unexpected option data")
+                .nbd_write(sock)
+                .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+        let images = server_shard.state.lock().unwrap().list_images();
+        let images = images
+            .into_iter()
+            .filter(|(_, desc)| server_shard.access(desc).is_some())
+            .map(|(_, desc)| desc.full_name());
         for image in images {
-            let mut data = BytesMut::new();
-            data.put_u32_ne(image.as_bytes().len() as u32);
+            let mut data = Vec::with_capacity(4 + image.len());
+            data.put_u32(image.len() as u32);
             data.put_slice(image.as_bytes());
-            data.put_slice(&[0, 0, 0, 0]);
-            let reply = OptReply {
-                option: opt,
+            OptReply {
+                option: opt as u32,
                 reply: NbdOptReply::Server,
-                data: Vec::from(data.deref()),
+                data,
+            }
+            .nbd_write(sock)
+            .await?;
+        }
+        OptReply::ack(opt).nbd_write(sock).await?;
+        Ok(OptionHandleState::Continue)
+    }
+}
+
+// NBD_OPT_STARTTLS (5)
+#[derive(Debug, Default)]
+struct StartTlsOptionHandler {}
+
+#[async_trait]
+impl OptionHandler for StartTlsOptionHandler {
+    async fn handle_option(
+        &self,
+        server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        let config = server_shard.config.clone();
+        let acceptor = match (&config.tls_acceptor, server_shard.listener.tls_mode) {
+            (Some(acceptor), TlsMode::Optional | TlsMode::Required) => acceptor,
+            _ => {
+                OptReply::error(opt, NbdOptReply::ErrUnsup, "tls not supported")
+                    .nbd_write(sock)
+                    .await?;
+                return Ok(OptionHandleState::Continue);
+            }
+        };
+        if !data.is_empty() || sock.is_tls() {
+            OptReply::error(opt, NbdOptReply::ErrInvalid, "invalid starttls request")
+                .nbd_write(sock)
+                .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+
+        OptReply::ack(opt).nbd_write(sock).await?;
+        sock.flush().await?;
+        if let Err(err) = sock.upgrade(acceptor).await {
+            error!(?err, "tls handshake failed");
+            return Err(err);
+        }
+        server_shard.identity = sock.client_identity();
+        info!(identity = ?server_shard.identity, "tls established");
+        server_shard.reset_negotiation();
+        Ok(OptionHandleState::Continue)
+    }
+}
+
+// NBD_OPT_INFO (6) and NBD_OPT_GO (7)
+#[derive(Debug, Default)]
+struct InfoOptionHandler {}
+
+// Read a string prefixed by its 32 bit length.
+fn get_string(data: &mut &[u8]) -> Option<String> {
+    if data.remaining() < 4 {
+        return None;
+    }
+    let len = data.get_u32() as usize;
+    if data.remaining() < len {
+        return None;
+    }
+    let s = String::from_utf8(data[..len].to_vec()).ok()?;
+    data.advance(len);
+    Some(s)
+}
+
+impl InfoOptionHandler {
+    // Parse the name of the export and the list of requested information types.
+    fn parse(mut data: &[u8]) -> Option<(String, Vec<u16>)> {
+        let name = get_string(&mut data)?;
+        if data.remaining() < 2 {
+            return None;
+        }
+        let info_count = data.get_u16() as usize;
+        if data.remaining() != info_count * 2 {
+            return None;
+        }
+        let infos = (0..info_count).map(|_| data.get_u16()).collect();
+        Some((name, infos))
+    }
+}
+
+#[async_trait]
+impl OptionHandler for InfoOptionHandler {
+    async fn handle_option(
+        &self,
+        server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        let Some((name, infos)) = Self::parse(&data) else {
+            OptReply::error(opt, NbdOptReply::ErrInvalid, "malformed info request")
+                .nbd_write(sock)
+                .await?;
+            return Ok(OptionHandleState::Continue);
+        };
+        debug!(name, ?infos, "info request");
+
+        let image = match server_shard.open_image(&name).await {
+            Ok(image) => image,
+            Err(reply) => {
+                OptReply::error(opt, reply, &format!("can not open image {}", name))
+                    .nbd_write(sock)
+                    .await?;
+                return Ok(OptionHandleState::Continue);
+            }
+        };
+        let info = image.info();
+        let block_size = block_size(&info);
+        if block_size.needs_alignment()
+            && !infos.contains(&(NbdInfo::BlockSize as u16))
+            && opt == NbdOpt::Go
+        {
+            info!(name, ?block_size, "client did not request block size");
+            OptReply::error(
+                opt,
+                NbdOptReply::ErrBlockSizeReqd,
+                "export needs block size negotiation",
+            )
+            .nbd_write(sock)
+            .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+        if opt == NbdOpt::Go {
+            if let Err(refusal) = server_shard.admit_export(&name) {
+                OptReply::error(opt, refusal.reply(), refusal.message())
+                    .nbd_write(sock)
+                    .await?;
+                return Ok(OptionHandleState::Continue);
+            }
+        }
+
+        let mut payload = BytesMut::new();
+        payload.put_u64(export_size(&info));
+        payload.put_u16(server_shard.tx_flags(&image).bits());
+        OptReply::info(opt, NbdInfo::Export, &payload)
+            .nbd_write(sock)
+            .await?;
+
+        for info_type in infos {
+            let reply = match FromPrimitive::from_u16(info_type) {
+                Some(NbdInfo::Name) => OptReply::info(opt, NbdInfo::Name, name.as_bytes()),
+                Some(NbdInfo::Description) => match &info.description {
+                    Some(description) => {
+                        OptReply::info(opt, NbdInfo::Description, description.as_bytes())
+                    }
+                    None => continue,
+                },
+                Some(NbdInfo::BlockSize) => {
+                    let mut payload = BytesMut::new();
+                    payload.put_u32(block_size.minimum);
+                    payload.put_u32(block_size.preferred);
+                    payload.put_u32(block_size.maximum);
+                    OptReply::info(opt, NbdInfo::BlockSize, &payload)
+                }
+                // NBD_INFO_EXPORT is always sent, unknown types are ignored.
+                _ => continue,
             };
             reply.nbd_write(sock).await?;
         }
-        OptReply {
-            option: opt,
-            reply: NbdOptReply::Ack,
-            data: Vec::new(),
+        OptReply::ack(opt).nbd_write(sock).await?;
+
+        if opt == NbdOpt::Go {
+            server_shard.set_image(&name, image);
+            Ok(OptionHandleState::End)
+        } else {
+            Ok(OptionHandleState::Continue)
+        }
+    }
+}
+
+// NBD_OPT_STRUCTURED_REPLY (8)
+#[derive(Debug, Default)]
+struct StructuredReplyOptionHandler {}
+
+#[async_trait]
+impl OptionHandler for StructuredReplyOptionHandler {
+    async fn handle_option(
+        &self,
+        server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        if !data.is_empty() {
+            OptReply::error(opt, NbdOptReply::ErrInvalid, "unexpected option data")
+                .nbd_write(sock)
+                .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+        if server_shard.extended_headers {
+            OptReply::error(
+                opt,
+                NbdOptReply::ErrInvalid,
+                "extended headers already negotiated",
+            )
+            .nbd_write(sock)
+            .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+        server_shard.structured_reply = true;
+        OptReply::ack(opt).nbd_write(sock).await?;
+        Ok(OptionHandleState::Continue)
+    }
+}
+
+// NBD_OPT_EXTENDED_HEADERS (11)
+#[derive(Debug, Default)]
+struct ExtendedHeadersOptionHandler {}
+
+#[async_trait]
+impl OptionHandler for ExtendedHeadersOptionHandler {
+    async fn handle_option(
+        &self,
+        server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        if !data.is_empty() {
+            OptReply::error(opt, NbdOptReply::ErrInvalid, "unexpected option data")
+                .nbd_write(sock)
+                .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+        // Extended headers imply structured replies.
+        server_shard.extended_headers = true;
+        server_shard.structured_reply = true;
+        OptReply::ack(opt).nbd_write(sock).await?;
+        Ok(OptionHandleState::Continue)
+    }
+}
+
+// NBD_OPT_LIST_META_CONTEXT (9) and NBD_OPT_SET_META_CONTEXT (10)
+#[derive(Debug, Default)]
+struct MetaContextOptionHandler {}
+
+impl MetaContextOptionHandler {
+    // Parse the name of the export and the list of queries.
+    fn parse(mut data: &[u8]) -> Option<(String, Vec<String>)> {
+        let name = get_string(&mut data)?;
+        if data.remaining() < 4 {
+            return None;
+        }
+        let query_count = data.get_u32() as usize;
+        if query_count > MAX_META_CONTEXT_QUERIES {
+            return None;
+        }
+        let queries = (0..query_count)
+            .map(|_| get_string(&mut data))
+            .collect::<Option<Vec<_>>>()?;
+        if data.has_remaining() {
+            return None;
+        }
+        Some((name, queries))
+    }
+}
+
+#[async_trait]
+impl OptionHandler for MetaContextOptionHandler {
+    async fn handle_option(
+        &self,
+        server_shard: &mut ServerShard,
+        opt: NbdOpt,
+        data: Vec<u8>,
+        sock: &mut Connection,
+    ) -> IoResult<OptionHandleState> {
+        let list = opt == NbdOpt::ListMetaContext;
+        if !list && !server_shard.structured_reply {
+            OptReply::error(
+                opt,
+                NbdOptReply::ErrInvalid,
+                "structured reply not negotiated",
+            )
+            .nbd_write(sock)
+            .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+        let Some((name, queries)) = Self::parse(&data) else {
+            OptReply::error(
+                opt,
+                NbdOptReply::ErrInvalid,
+                "malformed meta context request",
+            )
+            .nbd_write(sock)
+            .await?;
+            return Ok(OptionHandleState::Continue);
+        };
+        debug!(name, ?queries, "meta context request");
+
+        if let Err(reply) = server_shard.find_image(&name) {
+            OptReply::error(opt, reply, "unknown export")
+                .nbd_write(sock)
+                .await?;
+            return Ok(OptionHandleState::Continue);
+        }
+
+        let mut ids: Vec<u32> = if list && queries.is_empty() {
+            server_shard.config.query_meta_contexts("", true)
+        } else {
+            queries
+                .iter()
+                .flat_map(|query| server_shard.config.query_meta_contexts(query, list))
+                .collect()
+        };
+        ids.sort_unstable();
+        ids.dedup();
+
+        let config = server_shard.config.clone();
+        for id in ids.iter().copied() {
+            let context = config.meta_context(id).unwrap();
+            let mut data = Vec::with_capacity(4 + context.name().len());
+            // The id is meaningless when listing.
+            data.put_u32(if list { 0 } else { id });
+            data.put_slice(context.name().as_bytes());
+            OptReply {
+                option: opt as u32,
+                reply: NbdOptReply::MetaContext,
+                data,
+            }
+            .nbd_write(sock)
+            .await?;
+        }
+        OptReply::ack(opt).nbd_write(sock).await?;
+
+        if !list {
+            server_shard.meta_export = Some(name);
+            server_shard.meta_contexts = ids;
         }
-        .nbd_write(sock)
-        .await?;
         Ok(OptionHandleState::Continue)
     }
 }
+
+// Metadata contexts, answering NBD_CMD_BLOCK_STATUS.
+#[async_trait]
+trait MetaContext: Send + Sync {
+    fn name(&self) -> &str;
+    // Return (length, flags) descriptors starting at offset.
+    async fn block_status(
+        &self,
+        image: &Image,
+        offset: u64,
+        length: u64,
+    ) -> IoResult<Vec<(u64, u32)>>;
+}
+
+// base:allocation
+#[derive(Debug, Default)]
+struct BaseAllocationMetaContext {}
+
+#[async_trait]
+impl MetaContext for BaseAllocationMetaContext {
+    fn name(&self) -> &str {
+        NBD_META_CONTEXT_BASE_ALLOCATION
+    }
+
+    async fn block_status(
+        &self,
+        image: &Image,
+        offset: u64,
+        length: u64,
+    ) -> IoResult<Vec<(u64, u32)>> {
+        let mut extents: Vec<(u64, u32)> = Vec::new();
+        for extent in image.block_status(offset, length).await? {
+            let flags = extent.flags.bits();
+            match extents.last_mut() {
+                // Merge with the previous one if possible.
+                Some((len, last_flags)) if *last_flags == flags => *len += extent.length,
+                _ if extent.length > 0 => extents.push((extent.length, flags)),
+                _ => {}
+            }
+        }
+        if extents.is_empty() {
+            return Err(IoErrorKind::InvalidData.into());
+        }
+        Ok(extents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::DuplexStream;
+    use tokio_rustls::{
+        client::TlsStream,
+        rustls::{
+            self,
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+            RootCertStore,
+        },
+        TlsConnector,
+    };
+
+    use super::*;
+    use crate::driver::{
+        driver_registry, DriverConfig, DriverImpl, Extent, ExtentFlags, ImageImpl,
+    };
+
+    // Either end of a duplex pipe, plain or with TLS.
+    trait Client: AsyncRead + AsyncWrite + Unpin {}
+
+    impl<T: AsyncRead + AsyncWrite + Unpin> Client for T {}
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    // Upgrade after NBD_OPT_STARTTLS was acked, presenting the client
+    // certificate if there is one.
+    async fn tls_connect(
+        client: DuplexStream,
+        cert: Option<(&str, &str)>,
+    ) -> TlsStream<DuplexStream> {
+        let mut roots = RootCertStore::empty();
+        for ca in CertificateDer::pem_file_iter(testdata("ca.pem")).unwrap() {
+            roots.add(ca.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match cert {
+            Some((cert, key)) => {
+                let certs = CertificateDer::pem_file_iter(testdata(cert))
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                let key = PrivateKeyDer::from_pem_file(testdata(key)).unwrap();
+                builder.with_client_auth_cert(certs, key).unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let name = ServerName::try_from("localhost").unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(name, client)
+            .await
+            .unwrap()
+    }
+
+    async fn test_server(
+        name: &str,
+        builder: ServerBuilder,
+        driver_config: &[(&str, &str)],
+    ) -> (Server, std::path::PathBuf) {
+        let root =
+            std::env::temp_dir().join(format!("nbdsrv-server-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("disk.img"), vec![0xa5u8; 65536]).unwrap();
+
+        let mut config = DriverConfig::new();
+        config.insert("root", root.to_str().unwrap());
+        for (key, value) in driver_config {
+            config.insert(key, value);
+        }
+        let drv = driver_registry().get_driver("fs", &config).unwrap();
+        let server = builder.build().unwrap();
+        server.add_image(&drv, "disk.img").await.unwrap();
+        (server, root)
+    }
+
+    // An in-memory image, for what the fs driver can not show. Reads at
+    // offset 0 wait for a permit of the read gate.
+    #[derive(Clone)]
+    struct MemImage {
+        data: Arc<Mutex<Vec<u8>>>,
+        description: Option<String>,
+        block_size: BlockSize,
+        caps: ImageCaps,
+        opens: Arc<AtomicUsize>,
+        read_gate: Arc<Semaphore>,
+        reads_started: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ImageImpl for MemImage {
+        fn name(&self) -> &str {
+            "disk"
+        }
+
+        fn info(&self) -> ImageInfo {
+            ImageInfo {
+                size: self.data.lock().unwrap().len(),
+                readonly: false,
+                description: self.description.clone(),
+                block_size: self.block_size,
+                rotational: false,
+            }
+        }
+
+        fn dup(&self) -> Box<dyn ImageImpl> {
+            Box::new(self.clone())
+        }
+
+        fn caps(&self) -> ImageCaps {
+            self.caps
+        }
+
+        async fn read(&self, offset: u64, length: usize) -> IoResult<Bytes> {
+            self.reads_started.fetch_add(1, Ordering::SeqCst);
+            if offset == 0 {
+                self.read_gate.acquire().await.unwrap().forget();
+            }
+            let data = self.data.lock().unwrap();
+            Ok(Bytes::copy_from_slice(&data[offset as usize..][..length]))
+        }
+
+        async fn write(&self, offset: u64, data: Bytes, _flags: WriteFlags) -> IoResult<()> {
+            self.data.lock().unwrap()[offset as usize..][..data.len()].copy_from_slice(&data);
+            Ok(())
+        }
+
+        async fn flush(&self) -> IoResult<()> {
+            Ok(())
+        }
+
+        async fn write_zeroes(&self, offset: u64, length: u64, flags: WriteFlags) -> IoResult<()> {
+            if flags.contains(WriteFlags::FAST_ZERO) {
+                return Err(IoErrorKind::Unsupported.into());
+            }
+            self.data.lock().unwrap()[offset as usize..][..length as usize].fill(0);
+            Ok(())
+        }
+
+        async fn cache(&self, _offset: u64, _length: u64) -> IoResult<()> {
+            Ok(())
+        }
+
+        // Every other 4K block is a hole.
+        async fn block_status(&self, offset: u64, length: u64) -> IoResult<Vec<Extent>> {
+            let end = offset + length;
+            let mut extents = Vec::new();
+            let mut start = offset;
+            while start < end {
+                let next = ((start / 4096 + 1) * 4096).min(end);
+                let flags = match start / 4096 % 2 {
+                    0 => ExtentFlags::empty(),
+                    _ => ExtentFlags::HOLE | ExtentFlags::ZERO,
+                };
+                extents.push(Extent {
+                    length: next - start,
+                    flags,
+                });
+                start = next;
+            }
+            Ok(extents)
+        }
+    }
+
+    // Serves its image as "mem/disk".
+    #[derive(Clone)]
+    struct MemDriver {
+        image: MemImage,
+    }
+
+    #[async_trait]
+    impl DriverImpl for MemDriver {
+        fn name(&self) -> &str {
+            "mem"
+        }
+
+        fn dup(&self) -> Box<dyn DriverImpl> {
+            Box::new(self.clone())
+        }
+
+        async fn get_image(&self, name: &str) -> IoResult<ImageDesc> {
+            Ok(ImageDesc {
+                driver_name: "mem".to_string(),
+                name: name.to_string(),
+            })
+        }
+
+        async fn open(&self, _image: &ImageDesc) -> IoResult<Image> {
+            self.image.opens.fetch_add(1, Ordering::SeqCst);
+            Ok(Image::from_impl(Box::new(self.image.clone())))
+        }
+    }
+
+    async fn mem_server(builder: ServerBuilder, image: MemImage) -> Server {
+        let server = builder.build().unwrap();
+        let drv = Driver::from_impl(Box::new(MemDriver { image }));
+        server.add_image(&drv, "disk").await.unwrap();
+        server
+    }
+
+    fn mem_image(size: usize) -> MemImage {
+        MemImage {
+            data: Arc::new(Mutex::new(vec![0xa5; size])),
+            description: Some("test disk".to_string()),
+            block_size: BlockSize::default(),
+            caps: ImageCaps::READ | ImageCaps::WRITE | ImageCaps::FLUSH,
+            opens: Arc::new(AtomicUsize::new(0)),
+            read_gate: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            reads_started: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    async fn send_option(client: &mut impl Client, opt: u32, data: &[u8]) {
+        client.write_u64(IHAVEOPT).await.unwrap();
+        client.write_u32(opt).await.unwrap();
+        client.write_u32(data.len() as u32).await.unwrap();
+        client.write_all(data).await.unwrap();
+    }
+
+    async fn read_option_reply(client: &mut impl Client) -> (u32, i32, Vec<u8>) {
+        assert_eq!(client.read_u64().await.unwrap(), proto::NBD_OPT_REPLY_MAGIC);
+        let opt = client.read_u32().await.unwrap();
+        let reply = client.read_i32().await.unwrap();
+        let mut data = vec![0; client.read_u32().await.unwrap() as usize];
+        client.read_exact(&mut data).await.unwrap();
+        (opt, reply, data)
+    }
+
+    async fn send_request(
+        client: &mut impl Client,
+        cmd: NbdCmd,
+        cookie: u64,
+        offset: u64,
+        length: u32,
+    ) {
+        let flags = NbdCmdFlag::empty();
+        send_request_with_flags(client, cmd, flags, cookie, offset, length).await;
+    }
+
+    async fn send_request_with_flags(
+        client: &mut impl Client,
+        cmd: NbdCmd,
+        flags: NbdCmdFlag,
+        cookie: u64,
+        offset: u64,
+        length: u32,
+    ) {
+        client.write_u32(NBD_REQUEST_MAGIC).await.unwrap();
+        client.write_u16(flags.bits()).await.unwrap();
+        client.write_u16(cmd as u16).await.unwrap();
+        client.write_u64(cookie).await.unwrap();
+        client.write_u64(offset).await.unwrap();
+        client.write_u32(length).await.unwrap();
+    }
+
+    // Flags, type and payload of a structured reply chunk.
+    async fn read_chunk(client: &mut impl Client, cookie: u64) -> (u16, u16, Vec<u8>) {
+        assert_eq!(client.read_u32().await.unwrap(), NBD_STRUCTURED_REPLY_MAGIC);
+        let flags = client.read_u16().await.unwrap();
+        let ty = client.read_u16().await.unwrap();
+        assert_eq!(client.read_u64().await.unwrap(), cookie);
+        let mut payload = vec![0; client.read_u32().await.unwrap() as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (flags, ty, payload)
+    }
+
+    async fn read_simple_reply(client: &mut impl Client) -> (u32, u64) {
+        assert_eq!(client.read_u32().await.unwrap(), NBD_SIMPLE_REPLY_MAGIC);
+        let error = client.read_u32().await.unwrap();
+        (error, client.read_u64().await.unwrap())
+    }
+
+    // The cookie is the command.
+    async fn send_extended_request(
+        client: &mut impl Client,
+        cmd: NbdCmd,
+        flags: NbdCmdFlag,
+        offset: u64,
+        length: u64,
+        payload: &[u8],
+    ) {
+        client.write_u32(NBD_EXTENDED_REQUEST_MAGIC).await.unwrap();
+        client.write_u16(flags.bits()).await.unwrap();
+        client.write_u16(cmd as u16).await.unwrap();
+        client.write_u64(cmd as u64).await.unwrap();
+        client.write_u64(offset).await.unwrap();
+        client.write_u64(length).await.unwrap();
+        client.write_all(payload).await.unwrap();
+    }
+
+    // Flags, type and payload of a reply chunk to send_extended_request.
+    async fn read_extended_chunk(
+        client: &mut impl Client,
+        cmd: NbdCmd,
+        offset: u64,
+    ) -> (u16, u16, Vec<u8>) {
+        assert_eq!(client.read_u32().await.unwrap(), NBD_EXTENDED_REPLY_MAGIC);
+        let flags = client.read_u16().await.unwrap();
+        let ty = client.read_u16().await.unwrap();
+        assert_eq!(client.read_u64().await.unwrap(), cmd as u64);
+        assert_eq!(client.read_u64().await.unwrap(), offset);
+        let mut payload = vec![0; client.read_u64().await.unwrap() as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (flags, ty, payload)
+    }
+
+    // Select the context for the export, returning its id.
+    async fn set_meta_context(client: &mut impl Client, export: &[u8], query: &[u8]) -> u32 {
+        let mut data = Vec::new();
+        data.put_u32(export.len() as u32);
+        data.put_slice(export);
+        data.put_u32(1);
+        data.put_u32(query.len() as u32);
+        data.put_slice(query);
+        send_option(client, NbdOpt::SetMetaContext as u32, &data).await;
+        let (_, reply, context) = read_option_reply(client).await;
+        assert_eq!(reply, NbdOptReply::MetaContext as i32);
+        assert_eq!(context[4..], *query);
+        let (_, reply, _) = read_option_reply(client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        u32::from_be_bytes(context[..4].try_into().unwrap())
+    }
+
+    // Export names sent in reply to NBD_OPT_LIST.
+    async fn list(client: &mut impl Client) -> Vec<String> {
+        send_option(client, NbdOpt::List as u32, b"").await;
+        let mut names = Vec::new();
+        loop {
+            let (_, reply, data) = read_option_reply(client).await;
+            if reply != NbdOptReply::Server as i32 {
+                assert_eq!(reply, NbdOptReply::Ack as i32);
+                return names;
+            }
+            names.push(String::from_utf8(data[4..].to_vec()).unwrap());
+        }
+    }
+
+    async fn handshake(client: &mut impl Client) {
+        assert_eq!(client.read_u64().await.unwrap(), INIT_PASSWD);
+        assert_eq!(client.read_u64().await.unwrap(), IHAVEOPT);
+        client.read_u16().await.unwrap();
+        client
+            .write_u32((NbdClientFlag::FIXED_NEWSTYLE | NbdClientFlag::NO_ZEROES).bits())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_duplex_negotiation_and_read() {
+        let (server, root) = test_server("duplex", ServerBuilder::new(), &[]).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+
+        assert_eq!(client.read_u64().await.unwrap(), INIT_PASSWD);
+        assert_eq!(client.read_u64().await.unwrap(), IHAVEOPT);
+        let handshake_flags = NbdHandshakeFlag::from_bits_retain(client.read_u16().await.unwrap());
+        assert!(handshake_flags.contains(NbdHandshakeFlag::FIXED_NEWSTYLE));
+        client
+            .write_u32((NbdClientFlag::FIXED_NEWSTYLE | NbdClientFlag::NO_ZEROES).bits())
+            .await
+            .unwrap();
+
+        // Unknown options are refused, but the session goes on.
+        send_option(&mut client, 1000, b"probe").await;
+        let (opt, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!((opt, reply), (1000, NbdOptReply::ErrUnsup as i32));
+
+        let name = b"fs/disk.img";
+        let mut data = Vec::new();
+        data.put_u32(name.len() as u32);
+        data.put_slice(name);
+        data.put_u16(0);
+        send_option(&mut client, NbdOpt::Go as u32, &data).await;
+        let (_, reply, info) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Info as i32);
+        assert_eq!(&info[2..10], &65536u64.to_be_bytes());
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+
+        send_request(&mut client, NbdCmd::Read, 7, 4096, 512).await;
+        assert_eq!(client.read_u32().await.unwrap(), NBD_SIMPLE_REPLY_MAGIC);
+        assert_eq!(client.read_u32().await.unwrap(), 0);
+        assert_eq!(client.read_u64().await.unwrap(), 7);
+        let mut buf = vec![0; 512];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+
+        // Unknown commands are refused, but the session goes on.
+        client.write_u32(NBD_REQUEST_MAGIC).await.unwrap();
+        client.write_u16(0).await.unwrap();
+        client.write_u16(1000).await.unwrap();
+        client.write_u64(8).await.unwrap();
+        client.write_u64(0).await.unwrap();
+        client.write_u32(512).await.unwrap();
+        assert_eq!(
+            read_simple_reply(&mut client).await,
+            (NbdError::Inval as u32, 8)
+        );
+        send_request(&mut client, NbdCmd::Flush, 9, 0, 0).await;
+        assert_eq!(read_simple_reply(&mut client).await, (0, 9));
+
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_info_and_go() {
+        let server = mem_server(ServerBuilder::new(), mem_image(8192)).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let info_request = |name: &[u8], infos: &[NbdInfo]| {
+            let mut data = Vec::new();
+            data.put_u32(name.len() as u32);
+            data.put_slice(name);
+            data.put_u16(infos.len() as u16);
+            for info in infos {
+                data.put_u16(*info as u16);
+            }
+            data
+        };
+
+        // Requested information comes after NBD_INFO_EXPORT, then the client
+        // stays in negotiation.
+        let infos = [NbdInfo::Name, NbdInfo::Description, NbdInfo::BlockSize];
+        let data = info_request(b"mem/disk", &infos);
+        send_option(&mut client, NbdOpt::Info as u32, &data).await;
+        let (_, reply, export) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Info as i32);
+        assert_eq!(export[..2], (NbdInfo::Export as u16).to_be_bytes());
+        assert_eq!(export[2..10], 8192u64.to_be_bytes());
+        let mut block_size = Vec::new();
+        block_size.put_u16(NbdInfo::BlockSize as u16);
+        block_size.put_u32(1);
+        block_size.put_u32(4096);
+        block_size.put_u32(MAX_REQUEST_DATA_LEN as u32);
+        let expected: [(NbdOptReply, &[u8]); 4] = [
+            (NbdOptReply::Info, b"\0\x01mem/disk"),
+            (NbdOptReply::Info, b"\0\x02test disk"),
+            (NbdOptReply::Info, &block_size),
+            (NbdOptReply::Ack, b""),
+        ];
+        for (reply, data) in expected {
+            let (opt, actual, actual_data) = read_option_reply(&mut client).await;
+            assert_eq!((opt, actual), (NbdOpt::Info as u32, reply as i32));
+            assert_eq!(actual_data, data);
+        }
+        assert_eq!(list(&mut client).await, ["mem/disk"]);
+
+        let data = info_request(b"mem/missing", &[]);
+        send_option(&mut client, NbdOpt::Info as u32, &data).await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::ErrUnknown as i32);
+        assert_eq!(
+            go(&mut client, b"mem/missing").await.0,
+            NbdOptReply::ErrUnknown as i32
+        );
+
+        assert_eq!(
+            go(&mut client, b"mem/disk").await.0,
+            NbdOptReply::Ack as i32
+        );
+        send_request(&mut client, NbdCmd::Read, 1, 0, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (0, 1));
+        client.read_exact(&mut [0; 512]).await.unwrap();
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_options() {
+        let server = mem_server(ServerBuilder::new(), mem_image(8192)).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+
+        // A name of the largest size the spec allows fits.
+        let mut data = Vec::new();
+        data.put_u32(4096);
+        data.put_slice(&[b'a'; 4096]);
+        data.put_u16(1);
+        data.put_u16(NbdInfo::BlockSize as u16);
+        send_option(&mut client, NbdOpt::Info as u32, &data).await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::ErrUnknown as i32);
+
+        // Larger data is skipped, the session goes on.
+        let data = vec![0; MAX_OPTION_DATA_LEN + 1];
+        send_option(&mut client, NbdOpt::Info as u32, &data).await;
+        let (opt, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(opt, NbdOpt::Info as u32);
+        assert_eq!(reply, NbdOptReply::ErrTooBig as i32);
+        assert_eq!(list(&mut client).await, ["mem/disk"]);
+
+        // Name longer than the data, trailing data after the information
+        // requests.
+        let mut short_name = Vec::new();
+        short_name.put_u32(16);
+        short_name.put_slice(b"mem/disk");
+        let mut trailing = Vec::new();
+        trailing.put_u32(8);
+        trailing.put_slice(b"mem/disk");
+        trailing.put_u16(0);
+        trailing.put_u8(0);
+        for opt in [NbdOpt::Info, NbdOpt::Go] {
+            for data in [&short_name, &trailing] {
+                send_option(&mut client, opt as u32, data).await;
+                let (actual, reply, _) = read_option_reply(&mut client).await;
+                assert_eq!(actual, opt as u32);
+                assert_eq!(reply, NbdOptReply::ErrInvalid as i32);
+            }
+        }
+
+        let (reply, _) = go(&mut client, b"mem/disk").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_size_constraints() {
+        let mut image = mem_image(8192 + 100);
+        image.block_size.minimum = 512;
+        let server = mem_server(ServerBuilder::new(), image).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+
+        // The constraints must be asked for before NBD_OPT_GO.
+        let mut data = Vec::new();
+        data.put_u32(8);
+        data.put_slice(b"mem/disk");
+        data.put_u16(0);
+        send_option(&mut client, NbdOpt::Go as u32, &data).await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::ErrBlockSizeReqd as i32);
+
+        // The partial block at the end is left out.
+        let mut data = Vec::new();
+        data.put_u32(8);
+        data.put_slice(b"mem/disk");
+        data.put_u16(1);
+        data.put_u16(NbdInfo::BlockSize as u16);
+        send_option(&mut client, NbdOpt::Go as u32, &data).await;
+        let (_, reply, export) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Info as i32);
+        assert_eq!(export[2..10], 8192u64.to_be_bytes());
+        let (_, reply, block_size) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Info as i32);
+        assert_eq!(block_size[2..6], 512u32.to_be_bytes());
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+
+        let inval = (NbdError::Inval as u32, 1);
+        send_request(&mut client, NbdCmd::Read, 1, 256, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, inval);
+        send_request(&mut client, NbdCmd::Read, 1, 0, 256).await;
+        assert_eq!(read_simple_reply(&mut client).await, inval);
+        send_request(&mut client, NbdCmd::Write, 1, 256, 512).await;
+        client.write_all(&[0; 512]).await.unwrap();
+        assert_eq!(read_simple_reply(&mut client).await, inval);
+        send_request(&mut client, NbdCmd::Read, 1, 8192, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, inval);
+
+        send_request(&mut client, NbdCmd::Read, 2, 7680, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (0, 2));
+        let mut data = [0; 512];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [0xa5; 512]);
+
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tx_flags_follow_image() {
+        let config = [("readonly", "true")];
+        let (server, root) = test_server("readonly", ServerBuilder::new(), &config).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let (reply, tx_flags) = go(&mut client, b"fs/disk.img").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(tx_flags.contains(NbdTxFlag::READ_ONLY));
+        assert!(!tx_flags.intersects(NbdTxFlag::SEND_TRIM | NbdTxFlag::SEND_WRITE_ZEROES));
+
+        // Writes of any kind are refused before looking at the image.
+        let perm = NbdError::Perm as u32;
+        send_request(&mut client, NbdCmd::Write, 1, 0, 512).await;
+        client.write_all(&[0; 512]).await.unwrap();
+        assert_eq!(read_simple_reply(&mut client).await, (perm, 1));
+        send_request(&mut client, NbdCmd::Trim, 2, 0, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (perm, 2));
+        let disk = std::fs::read(root.join("disk.img")).unwrap();
+        assert!(disk.iter().all(|b| *b == 0xa5));
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // Writable, but without trim and zeroing.
+        let server = mem_server(ServerBuilder::new(), mem_image(8192)).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let (reply, tx_flags) = go(&mut client, b"mem/disk").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(!tx_flags.contains(NbdTxFlag::READ_ONLY));
+        assert!(tx_flags.contains(NbdTxFlag::SEND_FLUSH));
+        assert!(!tx_flags.intersects(NbdTxFlag::SEND_TRIM | NbdTxFlag::SEND_WRITE_ZEROES));
+        let inval = NbdError::Inval as u32;
+        send_request(&mut client, NbdCmd::Trim, 1, 0, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (inval, 1));
+        send_request(&mut client, NbdCmd::WriteZeroes, 2, 0, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (inval, 2));
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_replies() {
+        let mut image = mem_image(8192);
+        image.read_gate = Arc::new(Semaphore::new(0));
+        let gate = image.read_gate.clone();
+        let server = mem_server(ServerBuilder::new(), image).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let (reply, _) = go(&mut client, b"mem/disk").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+
+        // The first read is held by the image, the second overtakes it.
+        send_request(&mut client, NbdCmd::Read, 1, 0, 512).await;
+        send_request(&mut client, NbdCmd::Read, 2, 512, 512).await;
+        let mut data = [0; 512];
+        assert_eq!(read_simple_reply(&mut client).await, (0, 2));
+        client.read_exact(&mut data).await.unwrap();
+        gate.add_permits(1);
+        assert_eq!(read_simple_reply(&mut client).await, (0, 1));
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [0xa5; 512]);
+
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_inflight_requests() {
+        let mut image = mem_image(8192);
+        image.read_gate = Arc::new(Semaphore::new(0));
+        let gate = image.read_gate.clone();
+        let started = image.reads_started.clone();
+        let server = mem_server(ServerBuilder::new().max_inflight_requests(2), image).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let (reply, _) = go(&mut client, b"mem/disk").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+
+        // The third request is not read while two are in flight.
+        for cookie in 1..=3 {
+            send_request(&mut client, NbdCmd::Read, cookie, 0, 512).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+
+        let mut data = [0; 512];
+        gate.add_permits(1);
+        let (error, _) = read_simple_reply(&mut client).await;
+        assert_eq!(error, 0);
+        client.read_exact(&mut data).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+
+        gate.add_permits(2);
+        for _ in 0..2 {
+            let (error, _) = read_simple_reply(&mut client).await;
+            assert_eq!(error, 0);
+            client.read_exact(&mut data).await.unwrap();
+        }
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multi_conn() {
+        let mut image = mem_image(8192);
+        image.caps |= ImageCaps::MULTI_CONN;
+        let opens = image.opens.clone();
+        let server = mem_server(ServerBuilder::new(), image).await;
+        let mut clients = Vec::new();
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+            conns.push(tokio::spawn(server.clone().serve_connection(sock)));
+            handshake(&mut client).await;
+            let (reply, tx_flags) = go(&mut client, b"mem/disk").await;
+            assert_eq!(reply, NbdOptReply::Ack as i32);
+            assert!(tx_flags.contains(NbdTxFlag::CAN_MULTI_CONN));
+            clients.push(client);
+        }
+        // Both connections got the same image.
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+
+        // A flush on one connection covers a write on the other.
+        send_request(&mut clients[0], NbdCmd::Write, 1, 0, 512).await;
+        clients[0].write_all(&[0x5a; 512]).await.unwrap();
+        assert_eq!(read_simple_reply(&mut clients[0]).await, (0, 1));
+        send_request(&mut clients[1], NbdCmd::Flush, 2, 0, 0).await;
+        assert_eq!(read_simple_reply(&mut clients[1]).await, (0, 2));
+        send_request(&mut clients[1], NbdCmd::Read, 3, 0, 512).await;
+        assert_eq!(read_simple_reply(&mut clients[1]).await, (0, 3));
+        let mut data = [0; 512];
+        clients[1].read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [0x5a; 512]);
+
+        for client in &mut clients {
+            send_request(client, NbdCmd::Disc, 0, 0, 0).await;
+        }
+        for conn in conns {
+            conn.await.unwrap().unwrap();
+        }
+
+        // Not advertised unless the image allows it.
+        let server = mem_server(ServerBuilder::new(), mem_image(8192)).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let (reply, tx_flags) = go(&mut client, b"mem/disk").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(!tx_flags.contains(NbdTxFlag::CAN_MULTI_CONN));
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_flags() {
+        let mut image = mem_image(2 * READ_CHUNK_LEN);
+        image.caps |= ImageCaps::WRITE_ZEROES | ImageCaps::FAST_ZERO | ImageCaps::CACHE;
+        let server = mem_server(ServerBuilder::new(), image.clone()).await;
+        let query = NBD_META_CONTEXT_BASE_ALLOCATION.as_bytes();
+        let (mut client, sock) = tokio::io::duplex(4 * 1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        send_option(&mut client, NbdOpt::StructuredReply as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        let id = set_meta_context(&mut client, b"mem/disk", query).await;
+        let (reply, tx_flags) = go(&mut client, b"mem/disk").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(!tx_flags.contains(NbdTxFlag::SEND_FUA));
+        assert!(tx_flags.contains(NbdTxFlag::SEND_FAST_ZERO));
+        // The error of a reply without data, 0 for success.
+        let result = async |client: &mut DuplexStream, cookie| {
+            let (flags, ty, payload) = read_chunk(client, cookie).await;
+            assert_eq!(flags, NbdReplyFlag::DONE.bits());
+            if ty == NbdReplyType::None as u16 {
+                return 0;
+            }
+            assert_eq!(ty, NbdReplyType::Error as u16);
+            u32::from_be_bytes(payload[..4].try_into().unwrap())
+        };
+
+        // FUA on a write needs support from the image, elsewhere it is ignored.
+        let fua = NbdCmdFlag::FUA;
+        send_request_with_flags(&mut client, NbdCmd::Write, fua, 1, 0, 512).await;
+        client.write_all(&[0; 512]).await.unwrap();
+        assert_eq!(result(&mut client, 1).await, NbdError::Inval as u32);
+        send_request_with_flags(&mut client, NbdCmd::Flush, fua, 2, 0, 0).await;
+        assert_eq!(result(&mut client, 2).await, 0);
+
+        // A fast zero the image can not do is refused with NBD_ENOTSUP.
+        let fast_zero = NbdCmdFlag::FAST_ZERO;
+        let cmd = NbdCmd::WriteZeroes;
+        send_request_with_flags(&mut client, cmd, fast_zero, 3, 0, 512).await;
+        assert_eq!(result(&mut client, 3).await, NbdError::NotSup as u32);
+        assert_eq!(image.data.lock().unwrap()[..512], [0xa5; 512]);
+        send_request(&mut client, cmd, 4, 0, 512).await;
+        assert_eq!(result(&mut client, 4).await, 0);
+        assert_eq!(image.data.lock().unwrap()[..512], [0; 512]);
+
+        // The whole read comes in one chunk with NBD_CMD_FLAG_DF.
+        let length = 2 * READ_CHUNK_LEN as u32;
+        let df = NbdCmdFlag::DF;
+        send_request_with_flags(&mut client, NbdCmd::Read, df, 5, 0, length).await;
+        let (flags, ty, payload) = read_chunk(&mut client, 5).await;
+        assert_eq!(flags, NbdReplyFlag::DONE.bits());
+        assert_eq!(ty, NbdReplyType::OffsetData as u16);
+        assert_eq!(payload.len(), 8 + length as usize);
+
+        // Only the first extent with NBD_CMD_FLAG_REQ_ONE.
+        let cmd = NbdCmd::BlockStatus;
+        for (cookie, flags, extents) in [(6, NbdCmdFlag::empty(), 2), (7, NbdCmdFlag::REQ_ONE, 1)] {
+            send_request_with_flags(&mut client, cmd, flags, cookie, 0, 8192).await;
+            let (_, ty, payload) = read_chunk(&mut client, cookie).await;
+            assert_eq!(ty, NbdReplyType::BlockStatus as u16);
+            assert_eq!(payload[..4], id.to_be_bytes());
+            assert_eq!(payload.len(), 4 + 8 * extents);
+        }
+
+        send_request(&mut client, NbdCmd::Cache, 8, 0, 8192).await;
+        assert_eq!(result(&mut client, 8).await, 0);
+
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        // Without the capability NBD_CMD_CACHE is invalid.
+        let server = mem_server(ServerBuilder::new(), mem_image(8192)).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let (reply, tx_flags) = go(&mut client, b"mem/disk").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(!tx_flags.contains(NbdTxFlag::SEND_CACHE));
+        send_request(&mut client, NbdCmd::Cache, 1, 0, 8192).await;
+        assert_eq!(
+            read_simple_reply(&mut client).await,
+            (NbdError::Inval as u32, 1)
+        );
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_structured_read() {
+        let (server, root) = test_server("structured", ServerBuilder::new(), &[]).await;
+        let mut disk = vec![0xa5u8; READ_CHUNK_LEN];
+        disk.resize(2 * READ_CHUNK_LEN, 0);
+        std::fs::write(root.join("disk.img"), &disk).unwrap();
+
+        let (mut client, sock) = tokio::io::duplex(4 * 1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        send_option(&mut client, NbdOpt::StructuredReply as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert_eq!(
+            go(&mut client, b"fs/disk.img").await.0,
+            NbdOptReply::Ack as i32
+        );
+
+        // Read in chunks, the zeroed one is sent as a hole.
+        send_request(&mut client, NbdCmd::Read, 1, 0, disk.len() as u32).await;
+        let (flags, ty, payload) = read_chunk(&mut client, 1).await;
+        assert_eq!(flags, 0);
+        assert_eq!(ty, NbdReplyType::OffsetData as u16);
+        assert_eq!(payload[..8], 0u64.to_be_bytes());
+        assert_eq!(payload[8..], disk[..READ_CHUNK_LEN]);
+        let (flags, ty, payload) = read_chunk(&mut client, 1).await;
+        assert_eq!(flags, NbdReplyFlag::DONE.bits());
+        assert_eq!(ty, NbdReplyType::OffsetHole as u16);
+        assert_eq!(payload[..8], (READ_CHUNK_LEN as u64).to_be_bytes());
+        assert_eq!(payload[8..], (READ_CHUNK_LEN as u32).to_be_bytes());
+
+        // A failure is reported with its offset.
+        std::fs::File::options()
+            .write(true)
+            .open(root.join("disk.img"))
+            .unwrap()
+            .set_len(READ_CHUNK_LEN as u64)
+            .unwrap();
+        send_request(&mut client, NbdCmd::Read, 2, READ_CHUNK_LEN as u64, 512).await;
+        let (flags, ty, payload) = read_chunk(&mut client, 2).await;
+        assert_eq!(flags, NbdReplyFlag::DONE.bits());
+        assert_eq!(ty, NbdReplyType::ErrorOffset as u16);
+        assert_eq!(payload[..4], (NbdError::Io as u32).to_be_bytes());
+        assert_eq!(payload[6..], (READ_CHUNK_LEN as u64).to_be_bytes());
+
+        // Invalid requests get a plain error chunk.
+        send_request(&mut client, NbdCmd::Read, 3, disk.len() as u64, 512).await;
+        let (flags, ty, payload) = read_chunk(&mut client, 3).await;
+        assert_eq!(flags, NbdReplyFlag::DONE.bits());
+        assert_eq!(ty, NbdReplyType::Error as u16);
+        assert_eq!(payload[..4], (NbdError::Inval as u32).to_be_bytes());
+
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_status() {
+        let (server, root) = test_server("block-status", ServerBuilder::new(), &[]).await;
+        let query = NBD_META_CONTEXT_BASE_ALLOCATION.as_bytes();
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        send_option(&mut client, NbdOpt::StructuredReply as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        let id = set_meta_context(&mut client, b"fs/disk.img", query).await;
+        assert_eq!(
+            go(&mut client, b"fs/disk.img").await.0,
+            NbdOptReply::Ack as i32
+        );
+
+        send_request(&mut client, NbdCmd::BlockStatus, 1, 4096, 8192).await;
+        let (flags, ty, payload) = read_chunk(&mut client, 1).await;
+        assert_eq!(flags, NbdReplyFlag::DONE.bits());
+        assert_eq!(ty, NbdReplyType::BlockStatus as u16);
+        // All of the image is data.
+        let mut expected = Vec::new();
+        expected.put_u32(id);
+        expected.put_u32(8192);
+        expected.put_u32(0);
+        assert_eq!(payload, expected);
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        // With extended headers the contexts can be picked per request.
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        send_option(&mut client, NbdOpt::ExtendedHeaders as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        let id = set_meta_context(&mut client, b"fs/disk.img", query).await;
+        assert_eq!(
+            go(&mut client, b"fs/disk.img").await.0,
+            NbdOptReply::Ack as i32
+        );
+
+        let flags = NbdCmdFlag::PAYLOAD_LEN;
+        let mut payload = Vec::new();
+        payload.put_u64(8192);
+        payload.put_u32(id);
+        let cmd = NbdCmd::BlockStatus;
+        send_extended_request(
+            &mut client,
+            cmd,
+            flags,
+            4096,
+            payload.len() as u64,
+            &payload,
+        )
+        .await;
+        let (flags, ty, payload) = read_extended_chunk(&mut client, cmd, 4096).await;
+        assert_eq!(flags, NbdReplyFlag::DONE.bits());
+        assert_eq!(ty, NbdReplyType::BlockStatusExt as u16);
+        let mut expected = Vec::new();
+        expected.put_u32(id);
+        expected.put_u32(1);
+        expected.put_u64(8192);
+        expected.put_u64(0);
+        assert_eq!(payload, expected);
+
+        // Only selected contexts can be picked.
+        let flags = NbdCmdFlag::PAYLOAD_LEN;
+        let mut payload = Vec::new();
+        payload.put_u64(8192);
+        payload.put_u32(id + 1);
+        send_extended_request(
+            &mut client,
+            cmd,
+            flags,
+            4096,
+            payload.len() as u64,
+            &payload,
+        )
+        .await;
+        let (_, ty, payload) = read_extended_chunk(&mut client, cmd, 4096).await;
+        assert_eq!(ty, NbdReplyType::Error as u16);
+        assert_eq!(payload[..4], (NbdError::Inval as u32).to_be_bytes());
+
+        let flags = NbdCmdFlag::empty();
+        send_extended_request(&mut client, NbdCmd::Disc, flags, 0, 0, b"").await;
+        conn.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (server, root) = test_server("shutdown", ServerBuilder::new(), &[]).await;
+        let (mut negotiating, sock) = tokio::io::duplex(1024 * 1024);
+        let negotiating_conn = tokio::spawn(server.serve_connection(sock));
+        let (mut transmitting, sock) = tokio::io::duplex(1024 * 1024);
+        let transmitting_conn = tokio::spawn(server.serve_connection(sock));
+
+        handshake(&mut negotiating).await;
+        handshake(&mut transmitting).await;
+        send_option(&mut transmitting, NbdOpt::ExportName as u32, b"fs/disk.img").await;
+        let mut reply = vec![0; 10];
+        transmitting.read_exact(&mut reply).await.unwrap();
+
+        server.shutdown_handle().shutdown();
+
+        send_option(&mut negotiating, NbdOpt::List as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut negotiating).await;
+        assert_eq!(reply, NbdOptReply::ErrShutdown as i32);
+        send_option(&mut negotiating, NbdOpt::Abort as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut negotiating).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        negotiating_conn.await.unwrap().unwrap();
+
+        send_request(&mut transmitting, NbdCmd::Read, 1, 0, 512).await;
+        assert_eq!(
+            transmitting.read_u32().await.unwrap(),
+            NBD_SIMPLE_REPLY_MAGIC
+        );
+        assert_eq!(
+            transmitting.read_u32().await.unwrap(),
+            NbdError::Shutdown as u32
+        );
+        assert_eq!(transmitting.read_u64().await.unwrap(), 1);
+        send_request(&mut transmitting, NbdCmd::Disc, 0, 0, 0).await;
+        transmitting_conn.await.unwrap().unwrap();
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listener_negotiation_modes() {
+        // Where test_server puts its files.
+        let root =
+            std::env::temp_dir().join(format!("nbdsrv-server-listeners-{}", std::process::id()));
+        let (oldstyle, newstyle) = (root.join("oldstyle.sock"), root.join("newstyle.sock"));
+        let unix = |path: &Path| ListenAddr::Unix {
+            path: path.to_path_buf(),
+            mode: None,
+        };
+        let builder = ServerBuilder::new()
+            .default_export("fs/disk.img")
+            .listen_with(
+                unix(&oldstyle),
+                ListenerOptions::new().negotiation(NegotiationMode::Oldstyle),
+            )
+            .listen_with(unix(&newstyle), ListenerOptions::new());
+        let (mut server, root) = test_server("listeners", builder, &[]).await;
+        let shutdown = server.shutdown_handle();
+        let run = tokio::spawn(async move { server.run().await });
+        while !oldstyle.exists() || !newstyle.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The export right away on one, options on the other.
+        let mut client = tokio::net::UnixStream::connect(&oldstyle).await.unwrap();
+        assert_eq!(client.read_u64().await.unwrap(), INIT_PASSWD);
+        assert_eq!(client.read_u64().await.unwrap(), CLISERV_MAGIC);
+        assert_eq!(client.read_u64().await.unwrap(), 65536);
+        drop(client);
+        let mut client = tokio::net::UnixStream::connect(&newstyle).await.unwrap();
+        handshake(&mut client).await;
+        assert_eq!(list(&mut client).await, ["fs/disk.img"]);
+        drop(client);
+
+        shutdown.shutdown();
+        run.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeouts() {
+        let builder = ServerBuilder::new()
+            .option_idle_timeout(Some(Duration::from_millis(50)))
+            .transmission_idle_timeout(Some(Duration::from_millis(50)));
+        let (server, root) = test_server("timeouts", builder, &[]).await;
+
+        // Both are dropped without a word.
+        let (mut negotiating, sock) = tokio::io::duplex(1024 * 1024);
+        let negotiating_conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut negotiating).await;
+        negotiating_conn.await.unwrap().unwrap();
+        assert_eq!(
+            negotiating.read_u8().await.unwrap_err().kind(),
+            IoErrorKind::UnexpectedEof
+        );
+
+        let (mut transmitting, sock) = tokio::io::duplex(1024 * 1024);
+        let transmitting_conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut transmitting).await;
+        send_option(&mut transmitting, NbdOpt::ExportName as u32, b"fs/disk.img").await;
+        let mut reply = vec![0; 10];
+        transmitting.read_exact(&mut reply).await.unwrap();
+        transmitting_conn.await.unwrap().unwrap();
+        assert_eq!(
+            transmitting.read_u8().await.unwrap_err().kind(),
+            IoErrorKind::UnexpectedEof
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    // The final reply and the transmission flags.
+    async fn go(client: &mut impl Client, name: &[u8]) -> (i32, NbdTxFlag) {
+        let mut data = Vec::new();
+        data.put_u32(name.len() as u32);
+        data.put_slice(name);
+        data.put_u16(1);
+        data.put_u16(NbdInfo::BlockSize as u16);
+        send_option(client, NbdOpt::Go as u32, &data).await;
+        let mut tx_flags = NbdTxFlag::empty();
+        loop {
+            let (_, reply, info) = read_option_reply(client).await;
+            if reply != NbdOptReply::Info as i32 {
+                return (reply, tx_flags);
+            }
+            if info[..2] == (NbdInfo::Export as u16).to_be_bytes() {
+                tx_flags = NbdTxFlag::from_bits_retain(u16::from_be_bytes([info[10], info[11]]));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let builder = ServerBuilder::new()
+            .max_connections(2)
+            .max_connections_per_export(1);
+        let (server, root) = test_server("limits", builder, &[]).await;
+        let connect = || async {
+            let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+            let conn = tokio::spawn(server.serve_connection(sock));
+            handshake(&mut client).await;
+            (client, conn)
+        };
+        let (mut first, first_conn) = connect().await;
+        let (mut second, _second_conn) = connect().await;
+        let (mut third, third_conn) = connect().await;
+
+        assert_eq!(
+            go(&mut first, b"fs/disk.img").await.0,
+            NbdOptReply::Ack as i32
+        );
+        assert_eq!(
+            go(&mut second, b"fs/disk.img").await.0,
+            NbdOptReply::ErrPolicy as i32
+        );
+        send_option(&mut third, NbdOpt::List as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut third).await;
+        assert_eq!(reply, NbdOptReply::ErrShutdown as i32);
+        // Refused connections are closed after the first reply.
+        third_conn.await.unwrap().unwrap();
+        assert_eq!(
+            third.read_u8().await.unwrap_err().kind(),
+            IoErrorKind::UnexpectedEof
+        );
+
+        // The export is free again once the first client is gone.
+        send_request(&mut first, NbdCmd::Disc, 0, 0, 0).await;
+        first_conn.await.unwrap().unwrap();
+        assert_eq!(
+            go(&mut second, b"fs/disk.img").await.0,
+            NbdOptReply::Ack as i32
+        );
+
+        let limits = ConnectionLimits {
+            per_client: Some(1),
+            ..Default::default()
+        };
+        let mut state = ServerState::default();
+        let client = Some(IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert_eq!(state.admit_connection(&limits, client), Ok(()));
+        assert_eq!(
+            state.admit_connection(&limits, client),
+            Err(Refusal::ClientLimit)
+        );
+        assert_eq!(state.admit_connection(&limits, None), Ok(()));
+        state.release_connection(client);
+        assert_eq!(state.admit_connection(&limits, client), Ok(()));
+
+        // Nothing is counted for refused connections.
+        let limits = ConnectionLimits {
+            per_client: Some(0),
+            per_export: Some(0),
+            ..Default::default()
+        };
+        let mut state = ServerState::default();
+        assert_eq!(
+            state.admit_connection(&limits, client),
+            Err(Refusal::ClientLimit)
+        );
+        assert_eq!(
+            state.admit_export(&limits, "fs/disk.img"),
+            Err(Refusal::ExportLimit)
+        );
+        assert!(state.client_connections.is_empty());
+        assert!(state.export_connections.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // A refused client that sends nothing is dropped early.
+        let mut builder = ServerBuilder::new()
+            .max_connections(0)
+            .handshake_timeout(None);
+        builder.timeouts.refused_handshake = Duration::from_millis(20);
+        let (server, root) = test_server("refused-timeout", builder, &[]).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        tokio::time::timeout(Duration::from_secs(5), conn)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_starttls() {
+        let builder = ServerBuilder::new()
+            .tls_cert(testdata("server.pem"), testdata("server.key"))
+            .unwrap()
+            .tls_mode(TlsMode::Required);
+        let (server, root) = test_server("starttls", builder, &[]).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+
+        // Nothing but NBD_OPT_STARTTLS and NBD_OPT_ABORT before the upgrade.
+        send_option(&mut client, NbdOpt::List as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::ErrTlsReqd as i32);
+        assert_eq!(
+            go(&mut client, b"fs/disk.img").await.0,
+            NbdOptReply::ErrTlsReqd as i32
+        );
+
+        send_option(&mut client, NbdOpt::Starttls as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        let mut client = tls_connect(client, None).await;
+        assert_eq!(
+            go(&mut client, b"fs/disk.img").await.0,
+            NbdOptReply::Ack as i32
+        );
+        send_request(&mut client, NbdCmd::Read, 1, 0, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (0, 1));
+        let mut buf = vec![0; 512];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        // NBD_OPT_EXPORT_NAME can not be refused, the client is closed.
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        send_option(&mut client, NbdOpt::ExportName as u32, b"fs/disk.img").await;
+        conn.await.unwrap().unwrap();
+        assert_eq!(
+            client.read_u8().await.unwrap_err().kind(),
+            IoErrorKind::UnexpectedEof
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_export_access() {
+        let builder = ServerBuilder::new()
+            .tls_cert(testdata("server.pem"), testdata("server.key"))
+            .unwrap()
+            .tls_client_ca(testdata("ca.pem"))
+            .unwrap()
+            .tls_mode(TlsMode::Optional)
+            .grant(crate::access::ANY, "fs/disk.img", ExportAccess::ReadOnly)
+            .grant("tenant-a", "fs/other.img", ExportAccess::ReadWrite);
+        let (server, root) = test_server("access", builder, &[]).await;
+        std::fs::write(root.join("other.img"), vec![0u8; 65536]).unwrap();
+        let mut config = DriverConfig::new();
+        config.insert("root", root.to_str().unwrap());
+        let drv = driver_registry().get_driver("fs", &config).unwrap();
+        server.add_image(&drv, "other.img").await.unwrap();
+
+        // Anonymous clients only see what is granted to anyone, read-only.
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        assert_eq!(list(&mut client).await, ["fs/disk.img"]);
+        let mut data = Vec::new();
+        data.put_u32(12);
+        data.put_slice(b"fs/other.img");
+        data.put_u16(0);
+        send_option(&mut client, NbdOpt::Info as u32, &data).await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::ErrPolicy as i32);
+        assert_eq!(
+            go(&mut client, b"fs/other.img").await.0,
+            NbdOptReply::ErrPolicy as i32
+        );
+        let (reply, tx_flags) = go(&mut client, b"fs/disk.img").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(tx_flags.contains(NbdTxFlag::READ_ONLY));
+        send_request(&mut client, NbdCmd::Write, 1, 0, 512).await;
+        client.write_all(&[0; 512]).await.unwrap();
+        assert_eq!(
+            read_simple_reply(&mut client).await,
+            (NbdError::Perm as u32, 1)
+        );
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        // The tenant gets its own export as well.
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        send_option(&mut client, NbdOpt::Starttls as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        let mut client = tls_connect(client, Some(("client.pem", "client.key"))).await;
+        let mut exports = list(&mut client).await;
+        exports.sort();
+        assert_eq!(exports, ["fs/disk.img", "fs/other.img"]);
+        let (reply, tx_flags) = go(&mut client, b"fs/other.img").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(!tx_flags.contains(NbdTxFlag::READ_ONLY));
+        send_request(&mut client, NbdCmd::Write, 1, 0, 512).await;
+        client.write_all(&[0; 512]).await.unwrap();
+        assert_eq!(read_simple_reply(&mut client).await, (0, 1));
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_build_rejects_unusable_tls() {
+        let err = ServerBuilder::new()
+            .tls_mode(TlsMode::Required)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_holds_backend_slot() {
+        let builder = ServerBuilder::new()
+            .max_inflight_requests(2)
+            .request_timeout(Some(Duration::from_millis(20)));
+        let (server, root) = test_server("request-timeout", builder, &[]).await;
+        let mut shard = server.shard(server.config.listener_config(&ListenerOptions::new()), None);
+        shard.image = Some(shard.open_image("fs/disk.img").await.unwrap());
+        let req = Request::new(0, NbdCmd::Flush as u16, 1, 0, 0);
+        let deadline = Some(Instant::now() + Duration::from_millis(20));
+
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let stuck = |_| async move {
+            let _ = done_rx.await;
+            Ok(())
+        };
+        let err = shard.backend(&req, deadline, stuck).await.unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::TimedOut);
+        assert_eq!(shard.backend_slots.available_permits(), 1);
+
+        // The slot is back once the call returns.
+        done_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(shard.backend_slots.available_permits(), 2);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_transient_accept_errors() {
+        assert!(is_transient_accept_error(&IoError::from_raw_os_error(
+            libc::EMFILE
+        )));
+        assert!(is_transient_accept_error(&IoError::from_raw_os_error(
+            libc::ECONNABORTED
+        )));
+        assert!(is_transient_accept_error(&IoErrorKind::Interrupted.into()));
+        assert!(!is_transient_accept_error(&IoError::from_raw_os_error(
+            libc::EBADF
+        )));
+        assert!(!is_transient_accept_error(&IoError::from_raw_os_error(
+            libc::EINVAL
+        )));
+    }
+
+    #[test]
+    fn test_io_error_to_nbd() {
+        // Only the downgrade, the mapping itself is tested in proto.
+        let map = |cmd: NbdCmd, flags: NbdCmdFlag, errno| {
+            let req = Request::new(flags.bits(), cmd as u16, 0, 0, 0);
+            io_error_to_nbd(&req, IoError::from_raw_os_error(errno))
+        };
+        let fast_zero = NbdCmdFlag::FAST_ZERO;
+        let df = NbdCmdFlag::DF;
+        let none = NbdCmdFlag::empty();
+        assert_eq!(
+            map(NbdCmd::WriteZeroes, fast_zero, libc::EOPNOTSUPP),
+            NbdError::NotSup
+        );
+        assert_eq!(
+            map(NbdCmd::WriteZeroes, none, libc::EOPNOTSUPP),
+            NbdError::Inval
+        );
+        assert_eq!(map(NbdCmd::Trim, none, libc::EOPNOTSUPP), NbdError::Inval);
+        assert_eq!(map(NbdCmd::Read, df, libc::EOVERFLOW), NbdError::Overflow);
+        assert_eq!(map(NbdCmd::Read, none, libc::EOVERFLOW), NbdError::Inval);
+        assert_eq!(map(NbdCmd::Write, none, libc::EOVERFLOW), NbdError::Inval);
+    }
+
+    #[tokio::test]
+    async fn test_resize() {
+        let (server, root) =
+            test_server("resize", ServerBuilder::new(), &[("block_size", "4096")]).await;
+        let new_size = 5 * 1024 * 1024 * 1024 + 4096;
+
+        // Compact requests can resize up to the 32 bit limit.
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        let (reply, tx_flags) = go(&mut client, b"fs/disk.img").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(tx_flags.contains(NbdTxFlag::SEND_RESIZE));
+        send_request(&mut client, NbdCmd::Resize, 1, 0, 8192).await;
+        assert_eq!(read_simple_reply(&mut client).await, (0, 1));
+        let size = std::fs::metadata(root.join("disk.img")).unwrap().len();
+        assert_eq!(size, 8192);
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        // Larger sizes need extended headers.
+
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        handshake(&mut client).await;
+        send_option(&mut client, NbdOpt::ExtendedHeaders as u32, b"").await;
+        let (_, reply, _) = read_option_reply(&mut client).await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        let (reply, tx_flags) = go(&mut client, b"fs/disk.img").await;
+        assert_eq!(reply, NbdOptReply::Ack as i32);
+        assert!(tx_flags.contains(NbdTxFlag::SEND_RESIZE));
+
+        let flags = NbdCmdFlag::empty();
+        send_extended_request(&mut client, NbdCmd::Resize, flags, 0, new_size, b"").await;
+        let (flags, ty, _) = read_extended_chunk(&mut client, NbdCmd::Resize, 0).await;
+        assert_eq!(flags, NbdReplyFlag::DONE.bits());
+        assert_eq!(ty, NbdReplyType::None as u16);
+        let size = std::fs::metadata(root.join("disk.img")).unwrap().len();
+        assert_eq!(size, new_size);
+
+        // Sizes must be aligned like any request.
+        let flags = NbdCmdFlag::empty();
+        send_extended_request(&mut client, NbdCmd::Resize, flags, 0, new_size + 1, b"").await;
+        let (_, ty, payload) = read_extended_chunk(&mut client, NbdCmd::Resize, 0).await;
+        assert_eq!(ty, NbdReplyType::Error as u16);
+        assert_eq!(payload[..4], (NbdError::Inval as u32).to_be_bytes());
+
+        send_extended_request(&mut client, NbdCmd::Disc, flags, 0, 0, b"").await;
+        conn.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oldstyle_and_plain_newstyle() {
+        let builder = ServerBuilder::new()
+            .negotiation(NegotiationMode::Oldstyle)
+            .default_export("fs/disk.img");
+        let (server, root) = test_server("oldstyle", builder, &[]).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        assert_eq!(client.read_u64().await.unwrap(), INIT_PASSWD);
+        assert_eq!(client.read_u64().await.unwrap(), CLISERV_MAGIC);
+        assert_eq!(client.read_u64().await.unwrap(), 65536);
+        let tx_flags = NbdTxFlag::from_bits_retain(client.read_u32().await.unwrap() as u16);
+        assert!(tx_flags.contains(NbdTxFlag::HAS_FLAGS));
+        let mut zeros = [0xffu8; 124];
+        client.read_exact(&mut zeros).await.unwrap();
+        assert_eq!(zeros, [0; 124]);
+
+        send_request(&mut client, NbdCmd::Read, 1, 0, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (0, 1));
+        let mut buf = vec![0; 512];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(buf.iter().all(|b| *b == 0xa5));
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let builder = ServerBuilder::new().negotiation(NegotiationMode::Newstyle);
+        let (server, root) = test_server("newstyle", builder, &[]).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        assert_eq!(client.read_u64().await.unwrap(), INIT_PASSWD);
+        assert_eq!(client.read_u64().await.unwrap(), IHAVEOPT);
+        assert_eq!(client.read_u16().await.unwrap(), 0);
+        client.write_u32(0).await.unwrap();
+        send_option(&mut client, NbdOpt::ExportName as u32, b"fs/disk.img").await;
+        assert_eq!(client.read_u64().await.unwrap(), 65536);
+        let tx_flags = NbdTxFlag::from_bits_retain(client.read_u16().await.unwrap());
+        assert!(tx_flags.contains(NbdTxFlag::HAS_FLAGS));
+        client.read_exact(&mut zeros).await.unwrap();
+        assert_eq!(zeros, [0; 124]);
+        send_request(&mut client, NbdCmd::Read, 2, 512, 512).await;
+        assert_eq!(read_simple_reply(&mut client).await, (0, 2));
+        client.read_exact(&mut buf).await.unwrap();
+        send_request(&mut client, NbdCmd::Disc, 0, 0, 0).await;
+        conn.await.unwrap().unwrap();
+
+        // Without option replies, any other option ends the session.
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        let conn = tokio::spawn(server.serve_connection(sock));
+        client.read_exact(&mut [0; 18]).await.unwrap();
+        client.write_u32(0).await.unwrap();
+        send_option(&mut client, NbdOpt::List as u32, b"").await;
+        conn.await.unwrap().unwrap();
+        assert_eq!(
+            client.read_u8().await.unwrap_err().kind(),
+            IoErrorKind::UnexpectedEof
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oldstyle_needs_unaligned_export() {
+        let builder = ServerBuilder::new()
+            .negotiation(NegotiationMode::Oldstyle)
+            .default_export("fs/disk.img");
+        let (server, root) =
+            test_server("oldstyle-aligned", builder, &[("block_size", "4096")]).await;
+        let (mut client, sock) = tokio::io::duplex(1024 * 1024);
+        server.serve_connection(sock).await.unwrap();
+        assert_eq!(
+            client.read_u8().await.unwrap_err().kind(),
+            IoErrorKind::UnexpectedEof
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{debug, error};
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+use crate::utils::IoResult;
+
+// How a listener treats TLS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsMode {
+    // NBD_OPT_STARTTLS is not supported.
+    #[default]
+    Off,
+    // Clients may upgrade with NBD_OPT_STARTTLS.
+    Optional,
+    // Everything but NBD_OPT_STARTTLS and NBD_OPT_ABORT is refused until the
+    // client upgrades.
+    Required,
+}
+
+pub struct TlsConfig {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    // CA for client certificates. Clients without a certificate are still
+    // accepted, but have no identity.
+    client_ca: Option<Arc<RootCertStore>>,
+}
+
+impl Clone for TlsConfig {
+    fn clone(&self) -> Self {
+        TlsConfig {
+            certs: self.certs.clone(),
+            key: self.key.clone_key(),
+            client_ca: self.client_ca.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("certs", &self.certs.len())
+            .field("client_ca", &self.client_ca.as_ref().map(|ca| ca.len()))
+            .finish()
+    }
+}
+
+fn pem_error(err: rustls::pki_types::pem::Error) -> std::io::Error {
+    match err {
+        rustls::pki_types::pem::Error::Io(err) => err,
+        err => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+    }
+}
+
+impl TlsConfig {
+    // Load a PEM certificate chain and its private key.
+    pub fn load(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> IoResult<Self> {
+        let certs = CertificateDer::pem_file_iter(cert.as_ref())
+            .map_err(pem_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(pem_error)?;
+        if certs.is_empty() {
+            error!(cert = ?cert.as_ref(), "no certificate found");
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        let key = PrivateKeyDer::from_pem_file(key.as_ref()).map_err(pem_error)?;
+        let config = TlsConfig {
+            certs,
+            key,
+            client_ca: None,
+        };
+        // Catch mismatched certificate and key early.
+        config.acceptor()?;
+        Ok(config)
+    }
+
+    // Load the PEM CA certificates client certificates are verified against.
+    pub fn load_client_ca(&mut self, ca: impl AsRef<Path>) -> IoResult<()> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca.as_ref()).map_err(pem_error)? {
+            roots
+                .add(cert.map_err(pem_error)?)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        }
+        if roots.is_empty() {
+            error!(ca = ?ca.as_ref(), "no ca certificate found");
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        self.client_ca = Some(Arc::new(roots));
+        self.acceptor()?;
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> IoResult<TlsAcceptor> {
+        let invalid = |err| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?;
+        let builder = match &self.client_ca {
+            Some(roots) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider)
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(self.certs.clone(), self.key.clone_key())
+            .map_err(invalid)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+// Who is on the other end, taken from a verified client certificate: the
+// subject common names followed by the DNS, email and URI alternative names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub names: Vec<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_cert(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, cert) = x509_parser::certificate::X509Certificate::from_der(cert).ok()?;
+        let mut names: Vec<String> = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_string)
+            .collect();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => names.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        if names.is_empty() {
+            return None;
+        }
+        Some(ClientIdentity { names })
+    }
+}
+
+// A connection which may be upgraded to TLS by NBD_OPT_STARTTLS.
+pub enum MaybeTlsStream<S> {
+    Plain(S),
+    Tls(Box<TlsStream<S>>),
+    // Only seen if an upgrade failed half way, the connection is unusable.
+    Closed,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MaybeTlsStream<S> {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, MaybeTlsStream::Tls(_))
+    }
+
+    // Identity of a client which presented a verified certificate.
+    pub fn client_identity(&self) -> Option<ClientIdentity> {
+        let MaybeTlsStream::Tls(sock) = self else {
+            return None;
+        };
+        let cert = sock.get_ref().1.peer_certificates()?.first()?;
+        let identity = ClientIdentity::from_cert(cert);
+        debug!(?identity, "client certificate");
+        identity
+    }
+
+    pub async fn upgrade(&mut self, acceptor: &TlsAcceptor) -> IoResult<()> {
+        let MaybeTlsStream::Plain(sock) = std::mem::replace(self, MaybeTlsStream::Closed) else {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        };
+        let tls = acceptor.accept(sock).await?;
+        *self = MaybeTlsStream::Tls(Box::new(tls));
+        Ok(())
+    }
+}
+
+fn closed() -> std::io::Error {
+    std::io::ErrorKind::NotConnected.into()
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(sock) => Pin::new(sock).poll_read(cx, buf),
+            MaybeTlsStream::Tls(sock) => Pin::new(sock.as_mut()).poll_read(cx, buf),
+            MaybeTlsStream::Closed => Poll::Ready(Err(closed())),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(sock) => Pin::new(sock).poll_write(cx, buf),
+            MaybeTlsStream::Tls(sock) => Pin::new(sock.as_mut()).poll_write(cx, buf),
+            MaybeTlsStream::Closed => Poll::Ready(Err(closed())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(sock) => Pin::new(sock).poll_flush(cx),
+            MaybeTlsStream::Tls(sock) => Pin::new(sock.as_mut()).poll_flush(cx),
+            MaybeTlsStream::Closed => Poll::Ready(Err(closed())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(sock) => Pin::new(sock).poll_shutdown(cx),
+            MaybeTlsStream::Tls(sock) => Pin::new(sock.as_mut()).poll_shutdown(cx),
+            MaybeTlsStream::Closed => Poll::Ready(Err(closed())),
+        }
+    }
+}
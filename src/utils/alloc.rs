@@ -3,7 +3,7 @@ pub unsafe fn malloc<T>(value: T) -> *mut T {
     let size = std::mem::size_of::<T>();
     let mut ptr: *mut T = std::ptr::null_mut();
     let res = libc::posix_memalign(
-        std::mem::transmute(std::ptr::addr_of_mut!(ptr)),
+        std::ptr::addr_of_mut!(ptr) as *mut *mut libc::c_void,
         align,
         size,
     );
@@ -44,11 +44,7 @@ impl ListHead {
     }
 
     pub unsafe fn empty(head: *mut ListHead) -> bool {
-        if head.transmute().next == head {
-            true
-        } else {
-            false
-        }
+        head.transmute().next == head
     }
 
     pub unsafe fn del(entry: *mut ListHead) {